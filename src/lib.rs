@@ -1,176 +1,7773 @@
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::any::{Any, TypeId};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::future::Future;
-use std::sync::{Arc, Mutex};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::ops::Deref;
+#[cfg(feature = "serde")]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard,
+    RwLockWriteGuard,
+};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
 
-type ZCacheStore = Arc<Mutex<HashMap<String, (u128, Box<ZEntry>)>>>;
-static mut ZCACHE_STORE: Lazy<ZCacheStore> = Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+const SHARD_COUNT: usize = 16;
+// Events buffered for every subscriber before it starts lagging.
+const EVENT_CAPACITY: usize = 1024;
+
+type Shard<K, V, S> = RwLock<ShardMap<K, V, S>>;
+type ZCacheStore<K, V, S> = Arc<Store<K, V, S>>;
+type InFlightLocks<K> = Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>;
+// Keys computed by `get_or_compute`, and the condition signalled when one
+// of them is done.
+type ComputingKeys<K> = (Mutex<HashSet<K>>, Condvar);
+type ReaperSlot = Arc<Mutex<Option<Arc<AbortHandle>>>>;
+type SharedClock = Arc<RwLock<Arc<dyn Clock>>>;
+type EvictCallback<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+type EvictSlot<K, V> = Arc<RwLock<Option<EvictCallback<K, V>>>>;
+type Removed<K, V> = Vec<(K, CacheEntry<V>)>;
+type EntrySize<K, V> = fn(&K, &V) -> usize;
+type SizeLimit<V> = (usize, fn(&V) -> usize);
+type Refresher = Arc<dyn Fn() + Send + Sync>;
+type NamespaceStats = Mutex<HashMap<String, Arc<HitCounters>>>;
+// Shared with the reaper task, which prunes them.
+type TagIndex<K> = Arc<Mutex<HashMap<String, HashSet<K>>>>;
+type NegativeKeys<K> = Arc<Mutex<HashMap<K, u128>>>;
+type Breakers<K> = Arc<Mutex<HashMap<K, BreakerState>>>;
+// Keys with backend updates still running in spawned tasks, with their count.
+type PendingKeys<K> = Arc<Mutex<HashMap<K, usize>>>;
+// Emits a debug event with the `tracing` feature, and compiles to nothing
+// without it.
+#[cfg(feature = "tracing")]
+macro_rules! debug_event {
+    ($($field:tt)*) => {
+        tracing::debug!($($field)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_event {
+    ($($field:tt)*) => {};
+}
+
+mod key_debug {
+    // Keys are logged in debug events, so they need `Debug` with the `tracing`
+    // feature only.
+    #[cfg(feature = "tracing")]
+    pub trait KeyDebug: std::fmt::Debug {}
+    #[cfg(feature = "tracing")]
+    impl<T: std::fmt::Debug + ?Sized> KeyDebug for T {}
+
+    #[cfg(not(feature = "tracing"))]
+    pub trait KeyDebug {}
+    #[cfg(not(feature = "tracing"))]
+    impl<T: ?Sized> KeyDebug for T {}
+}
+use key_debug::KeyDebug;
+
+static DEFAULT_INSTANCE: Lazy<ZCacheInstance> =
+    Lazy::new(|| ZCacheInstance::with_capacity(*INITIAL_CAPACITY.get_or_init(|| 0)));
+// Set once, either by `init_with_capacity` or by the first use of the store.
+static INITIAL_CAPACITY: OnceLock<usize> = OnceLock::new();
+static NAMED_INSTANCES: Lazy<Mutex<HashMap<String, Arc<ZCacheInstance>>>> =
+    Lazy::new(Default::default);
+static ACCESS_TICK: AtomicU64 = AtomicU64::new(0);
+static VERSION: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Error, Debug)]
 #[non_exhaustive]
 pub enum ZCacheError {
     #[error("Failed fetching '{0}' zcache key")]
     FetchError(String),
+    #[error("Expected {expected} zcache entry, found {found}")]
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("Updating '{0}' zcache key overflowed")]
+    Overflow(String),
+    #[error("Fetching '{0}' zcache key timed out")]
+    Timeout(String),
+    #[error("Zcache entry of {size} bytes exceeds the {limit} bytes limit")]
+    EntryTooLarge { size: usize, limit: usize },
+    #[error("Circuit breaker of '{0}' zcache key is open")]
+    CircuitOpen(String),
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ZEntry {
     Int(i64),
     Float(f64),
     Text(String),
     Bool(bool),
+    Bytes(Vec<u8>),
+    List(Vec<ZEntry>),
+    Map(HashMap<String, ZEntry>),
+    /// Cached absence of a value, unlike a missing key.
+    Null,
 }
 
-pub struct ZCache {}
+/// Variant of a `ZEntry`, without its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ZEntryKind {
+    Int,
+    Float,
+    Text,
+    Bool,
+    Bytes,
+    List,
+    Map,
+    Null,
+}
 
-impl ZCache {
-    pub async fn fetch<F, Fut>(
-        key: &str,
-        expires_in: Option<Duration>,
-        f: F,
-    ) -> Result<ZEntry, ZCacheError>
-    where
-        F: FnOnce() -> Fut,
-        Fut: Future<Output = Option<ZEntry>>,
-    {
-        match Self::read(key) {
-            Some(value) => Ok(value),
-            None => match f().await {
-                Some(value) => {
-                    Self::write(key, value.clone(), expires_in).await;
-                    Ok(value)
-                }
-                None => Err(ZCacheError::FetchError(key.to_string())),
-            },
+impl ZEntryKind {
+    /// Name of the variant, e.g. `"Int"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ZEntryKind::Int => "Int",
+            ZEntryKind::Float => "Float",
+            ZEntryKind::Text => "Text",
+            ZEntryKind::Bool => "Bool",
+            ZEntryKind::Bytes => "Bytes",
+            ZEntryKind::List => "List",
+            ZEntryKind::Map => "Map",
+            ZEntryKind::Null => "Null",
         }
     }
+}
 
-    pub fn read(key: &str) -> Option<ZEntry> {
-        let key = key.to_string();
-        let cache = unsafe { ZCACHE_STORE.lock().unwrap() };
-        let result = cache.get(&key);
-        match result {
-            Some((valid_until, value)) => {
-                let valid_until = *valid_until;
-                if valid_until == 0 || valid_until > now_in_millis() {
-                    Some(*value.clone())
-                } else {
-                    None
+impl fmt::Display for ZEntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// `Float` entries are compared by their bit patterns, so `NaN` is equal to
+/// itself, but `0.0` is not equal to `-0.0`. This makes the equality total, so
+/// `ZEntry` also implements `Eq`.
+impl PartialEq for ZEntry {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ZEntry::Int(a), ZEntry::Int(b)) => a == b,
+            (ZEntry::Float(a), ZEntry::Float(b)) => a.to_bits() == b.to_bits(),
+            (ZEntry::Text(a), ZEntry::Text(b)) => a == b,
+            (ZEntry::Bool(a), ZEntry::Bool(b)) => a == b,
+            (ZEntry::Bytes(a), ZEntry::Bytes(b)) => a == b,
+            (ZEntry::List(a), ZEntry::List(b)) => a == b,
+            (ZEntry::Map(a), ZEntry::Map(b)) => a == b,
+            (ZEntry::Null, ZEntry::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ZEntry {}
+
+/// Renders scalars as bare values, `Bytes` as hex, and collections like
+/// `[1, hi]` and `{a: 1, b: 2}`, with map keys sorted.
+impl fmt::Display for ZEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZEntry::Int(value) => write!(f, "{}", value),
+            ZEntry::Float(value) => write!(f, "{}", value),
+            ZEntry::Text(value) => write!(f, "{}", value),
+            ZEntry::Bool(value) => write!(f, "{}", value),
+            ZEntry::Bytes(value) => {
+                write!(f, "0x")?;
+                value.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            ZEntry::List(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            ZEntry::Map(values) => {
+                let mut fields: Vec<_> = values.iter().collect();
+                fields.sort_by_key(|(key, _)| *key);
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.into_iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
                 }
+                write!(f, "}}")
             }
-            None => None,
+            ZEntry::Null => write!(f, "null"),
         }
     }
+}
 
-    pub async fn write(key: &str, value: ZEntry, expires_in: Option<Duration>) {
-        let key = key.to_string();
+impl ZEntry {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            ZEntry::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
 
-        let valid_until: u128 = match expires_in {
-            Some(duration) => now_in_millis() + duration.as_millis(),
-            None => 0,
-        };
-        unsafe {
-            ZCACHE_STORE
-                .lock()
-                .unwrap()
-                .insert(key, (valid_until, Box::new(value)));
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            ZEntry::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            ZEntry::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ZEntry::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            ZEntry::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[ZEntry]> {
+        match self {
+            ZEntry::List(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&HashMap<String, ZEntry>> {
+        match self {
+            ZEntry::Map(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, ZEntry::Null)
+    }
+
+    /// Approximate heap memory owned by the entry in bytes, i.e. the capacity
+    /// of its strings and collections. Scalars like `Int` own none, so they
+    /// report `0`.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            ZEntry::Text(value) => value.capacity(),
+            ZEntry::Bytes(value) => value.capacity(),
+            ZEntry::List(values) => {
+                values.capacity() * std::mem::size_of::<ZEntry>()
+                    + values.iter().map(ZEntry::heap_size).sum::<usize>()
+            }
+            ZEntry::Map(values) => {
+                values.capacity() * (std::mem::size_of::<String>() + std::mem::size_of::<ZEntry>())
+                    + values
+                        .iter()
+                        .map(|(key, value)| key.capacity() + value.heap_size())
+                        .sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns a field of a `Map` entry, `None` for other variants.
+    pub fn get(&self, field: &str) -> Option<&ZEntry> {
+        self.as_map()?.get(field)
+    }
+
+    pub fn into_text(self) -> Option<String> {
+        match self {
+            ZEntry::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            ZEntry::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn into_list(self) -> Option<Vec<ZEntry>> {
+        match self {
+            ZEntry::List(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn into_map(self) -> Option<HashMap<String, ZEntry>> {
+        match self {
+            ZEntry::Map(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn clear() {
-        unsafe {
-            ZCACHE_STORE = Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+    /// Serializes the entry to JSON, tagged with its variant name, e.g.
+    /// `{"Int":1}`, `{"Text":"hi"}` or `"Null"`.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// Parses an entry serialized with `to_json`.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<ZEntry, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn kind(&self) -> ZEntryKind {
+        match self {
+            ZEntry::Int(_) => ZEntryKind::Int,
+            ZEntry::Float(_) => ZEntryKind::Float,
+            ZEntry::Text(_) => ZEntryKind::Text,
+            ZEntry::Bool(_) => ZEntryKind::Bool,
+            ZEntry::Bytes(_) => ZEntryKind::Bytes,
+            ZEntry::List(_) => ZEntryKind::List,
+            ZEntry::Map(_) => ZEntryKind::Map,
+            ZEntry::Null => ZEntryKind::Null,
         }
     }
+
+    fn type_name(&self) -> &'static str {
+        self.kind().name()
+    }
 }
 
-fn now_in_millis() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards!")
-        .as_millis()
+/// Values with an approximate memory size, which can be limited with
+/// `set_max_bytes`.
+pub trait HeapSize {
+    /// Heap memory owned by the value. The size of the value itself is added
+    /// by the cache.
+    fn heap_size(&self) -> usize;
 }
 
-#[cfg(test)]
-mod tests {
-    use std::ops::Mul;
-    use std::thread::sleep;
+impl HeapSize for ZEntry {
+    fn heap_size(&self) -> usize {
+        ZEntry::heap_size(self)
+    }
+}
 
-    use super::*;
+macro_rules! impl_conversions {
+    ($type:ty, $variant:ident) => {
+        impl From<$type> for ZEntry {
+            fn from(value: $type) -> Self {
+                ZEntry::$variant(value)
+            }
+        }
 
-    #[tokio::test]
-    async fn read_write_works() {
-        ZCache::clear();
-        let cacheable = ZEntry::Int(1);
-        let one_second = Duration::from_secs(1);
-        ZCache::write("key1", cacheable, Some(one_second)).await;
-        let result = ZCache::read("key1");
+        impl TryFrom<ZEntry> for $type {
+            type Error = ZCacheError;
 
-        match result {
-            Some(ZEntry::Int(value)) => assert_eq!(value, 1),
-            _ => panic!("Unexpected value"),
+            fn try_from(entry: ZEntry) -> Result<Self, Self::Error> {
+                match entry {
+                    ZEntry::$variant(value) => Ok(value),
+                    other => Err(ZCacheError::TypeMismatch {
+                        expected: stringify!($variant),
+                        found: other.type_name(),
+                    }),
+                }
+            }
         }
+    };
+}
 
-        sleep(one_second.mul(2));
-        let result = ZCache::read("key1");
+impl_conversions!(i64, Int);
+impl_conversions!(f64, Float);
+impl_conversions!(String, Text);
+impl_conversions!(bool, Bool);
 
-        if result.is_some() {
-            panic!("Entry should be expired!");
+impl From<&str> for ZEntry {
+    fn from(value: &str) -> Self {
+        ZEntry::Text(value.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Valid entries removed by the entries or memory limits.
+    pub evictions: u64,
+    /// Expired entries removed from the store, including the ones removed by
+    /// the limits.
+    pub expirations: u64,
+    /// Current number of valid entries.
+    pub entries: usize,
+    /// Highest memory usage seen by writes, measured like by
+    /// `estimated_bytes`, but including expired entries still stored.
+    pub peak_bytes: usize,
+    /// Values not written because they exceed the `set_max_entry_bytes`
+    /// limit.
+    pub rejections: u64,
+}
+
+impl CacheStats {
+    /// Share of reads that found a valid entry, `0.0` if there were no reads.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
         }
+    }
+}
 
-        let cacheable = ZEntry::Text("cached text".to_string());
-        ZCache::write("key2", cacheable, None).await;
-        sleep(one_second.mul(2));
-        let result = ZCache::read("key2");
-        match result {
-            Some(ZEntry::Text(value)) => assert_eq!(value, "cached text".to_string()),
-            _ => panic!("Unexpected value"),
+/// Entries physically in the store, returned by `raw_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawStats {
+    pub live: usize,
+    /// Expired entries not yet removed by the reaper or `purge_expired`.
+    pub expired_present: usize,
+    pub total: usize,
+}
+
+/// Change of the cache published to `subscribe` receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheEvent<K = String> {
+    /// The key was written or its value was modified in place.
+    Written { key: K },
+    /// A valid entry was deleted or evicted.
+    Deleted { key: K },
+    /// An expired entry was removed, or an entry was expired with
+    /// `expire_now`.
+    Expired { key: K },
+    /// All the entries were removed with `clear`.
+    Cleared,
+}
+
+/// Timestamps of a cache entry, in milliseconds since `UNIX_EPOCH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub created_at: u128,
+    /// Time of the last read, or `created_at` if it wasn't read yet.
+    pub last_accessed: u128,
+    /// `None` if the entry never expires.
+    pub valid_until: Option<u128>,
+}
+
+/// Handle to the background reaper task started with `start_reaper`.
+#[derive(Debug, Clone)]
+pub struct ReaperHandle {
+    abort_handle: Arc<AbortHandle>,
+    slot: ReaperSlot,
+}
+
+impl ReaperHandle {
+    pub fn stop(&self) {
+        let mut reaper = lock(&self.slot);
+        self.abort_handle.abort();
+        if let Some(current) = reaper.as_ref() {
+            if Arc::ptr_eq(current, &self.abort_handle) {
+                *reaper = None;
+            }
         }
     }
+}
+
+#[derive(Default)]
+struct HitCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HitCounters {
+    fn record(&self, hit: bool) {
+        match hit {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
 
-    #[tokio::test]
-    async fn fetch_works() {
-        ZCache::clear();
-        let cacheable = ZEntry::Int(1);
-        let result = ZCache::fetch("key1", None, || async { Some(cacheable.clone()) }).await;
+    fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
 
-        match result {
-            Ok(ZEntry::Int(value)) => assert_eq!(value, 1),
-            _ => panic!("Unexpected value"),
+// Limit of `set_max_entry_bytes` and the number of values it rejected,
+// shared with background refreshes.
+struct EntryLimit<V> {
+    limit: Mutex<Option<SizeLimit<V>>>,
+    rejections: AtomicU64,
+}
+
+impl<V> EntryLimit<V> {
+    fn check(&self, value: &V) -> Result<(), ZCacheError> {
+        let Some((limit, heap_size)) = *lock(&self.limit) else {
+            return Ok(());
+        };
+        let size = heap_size(value);
+        if size <= limit {
+            return Ok(());
         }
+        self.rejections.fetch_add(1, Ordering::Relaxed);
+        debug_event!(op = "reject", size, limit);
+        Err(ZCacheError::EntryTooLarge { size, limit })
     }
+}
 
-    #[tokio::test]
-    async fn fetch_expiry_works() -> Result<(), ZCacheError> {
-        ZCache::clear();
-        let cacheable = ZEntry::Int(1);
-        let one_second = Duration::from_secs(1);
-        let result = ZCache::fetch("key1", Some(one_second), || async {
-            Some(cacheable.clone())
-        })
-        .await;
-        match result {
-            Ok(ZEntry::Int(value)) => assert_eq!(value, 1),
-            _ => panic!("Unexpected value"),
+struct CacheEntry<V> {
+    valid_until: u128,
+    created_at: u128,
+    // Time of the last read in millis and a global tick ordering reads for LRU.
+    last_accessed: AtomicU64,
+    access_tick: AtomicU64,
+    access_count: AtomicU64,
+    // LFU priority, the access count plus the store age at the last read.
+    priority: AtomicU64,
+    value: Arc<V>,
+    tags: Vec<String>,
+    // Entries written by `fetch_swr` are refreshed in the background once
+    // past `fresh_until`, while `refreshing` prevents duplicate refreshes.
+    fresh_until: u128,
+    refreshing: AtomicBool,
+    // Spawns the refresh of entries written by `fetch_refresh_ahead`.
+    refresher: Option<Refresher>,
+    // Taken from a global counter on every write, so it only ever grows,
+    // even if the key is deleted and written again.
+    version: u64,
+    // Size of the key and value counted in the store totals.
+    size: usize,
+    // Keys of the entry in the eviction order, kept only while a limit is set.
+    ranked: Option<(u64, u64)>,
+    expiring: Option<(u128, u64)>,
+}
+
+impl<V> CacheEntry<V> {
+    fn new(valid_until: u128, value: V, now: u128) -> Self {
+        Self {
+            valid_until,
+            created_at: now,
+            last_accessed: AtomicU64::new(now as u64),
+            access_tick: AtomicU64::new(next_tick()),
+            access_count: AtomicU64::new(0),
+            priority: AtomicU64::new(0),
+            value: Arc::new(value),
+            tags: Vec::new(),
+            fresh_until: 0,
+            refreshing: AtomicBool::new(false),
+            refresher: None,
+            version: next_version(),
+            size: 0,
+            ranked: None,
+            expiring: None,
         }
+    }
 
-        match ZCache::fetch("key1", Some(one_second), || async {
-            Some(cacheable.clone())
-        })
-        .await?
-        {
-            ZEntry::Int(value) => value,
-            _ => panic!("Unexpected type"),
+    fn with_stale_window(value: V, fresh_for: Duration, stale_for: Duration, now: u128) -> Self {
+        let expires_in = fresh_for.saturating_add(stale_for);
+        let mut entry = Self::new(valid_until(Some(expires_in), now), value, now);
+        entry.fresh_until = valid_until(Some(fresh_for), now);
+        entry
+    }
+
+    fn is_stale(&self, now: u128) -> bool {
+        self.fresh_until != 0 && self.fresh_until <= now
+    }
+
+    fn is_valid(&self, now: u128) -> bool {
+        is_valid(self.valid_until, now)
+    }
+
+    // Copies the value if readers still hold it, so callers should only call
+    // it once they're sure to modify the value.
+    fn value_mut(&mut self) -> &mut V
+    where
+        V: Clone,
+    {
+        self.version = next_version();
+        Arc::make_mut(&mut self.value)
+    }
+
+    fn set_value(&mut self, value: V) {
+        self.version = next_version();
+        self.value = Arc::new(value);
+    }
+
+    fn access(&self, now: u128, age: u64) -> Option<Arc<V>> {
+        if self.is_valid(now) {
+            self.last_accessed.store(now as u64, Ordering::Relaxed);
+            self.access_tick.store(next_tick(), Ordering::Relaxed);
+            let count = self.access_count.fetch_add(1, Ordering::Relaxed) + 1;
+            self.priority.store(count + age, Ordering::Relaxed);
+            Some(self.value.clone())
+        } else {
+            None
+        }
+    }
+
+    // Ranks only grow with reads, and are unique as the ticks are.
+    fn rank(&self, policy: EvictionPolicy) -> (u64, u64) {
+        let priority = match policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => self.priority.load(Ordering::Relaxed),
         };
+        (priority, self.access_tick.load(Ordering::Relaxed))
+    }
+}
 
-        sleep(one_second.mul(2));
-        let result = ZCache::read("key1");
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotEntry<K, V> {
+    key: K,
+    valid_until: u128,
+    value: V,
+}
 
-        if result.is_some() {
-            panic!("Entry should be expired!");
+/// Source of the current time used for expiry checks.
+pub trait Clock: Send + Sync {
+    /// Milliseconds since `UNIX_EPOCH`.
+    fn now_in_millis(&self) -> u128;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_in_millis(&self) -> u128 {
+        now_in_millis()
+    }
+}
+
+/// Clock which moves only when advanced manually, so tests can check expiry
+/// without sleeping. Starts at the current system time.
+#[derive(Debug)]
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: AtomicU64::new(now_in_millis() as u64),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now_in_millis(&self) -> u128 {
+        self.now.load(Ordering::SeqCst) as u128
+    }
+}
+
+/// Order in which entries are evicted when the cache is full. Expired
+/// entries are always evicted first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the least recently used entry.
+    #[default]
+    Lru,
+    /// Evicts the least frequently used entry. Reads are counted on top of
+    /// the count of the last evicted entry, so entries popular long ago are
+    /// eventually evicted too.
+    Lfu,
+}
+
+/// Circuit breaker settings of `fetch_with_breaker`.
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl BreakerConfig {
+    /// Opens the breaker of a key after `failure_threshold` consecutive
+    /// failures, for `cooldown`.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+        }
+    }
+}
+
+// Consecutive loader failures of a key, the end of its cooldown, and when
+// the state is forgotten if the key isn't fetched again.
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: u128,
+    forget_at: u128,
+}
+
+/// Cache configuration applied with `configure`.
+#[derive(Debug, Clone, Default)]
+pub struct ZCacheConfig {
+    default_ttl: Option<Duration>,
+    min_ttl: Option<Duration>,
+    max_ttl: Option<Duration>,
+    max_entries: Option<usize>,
+    reaper_interval: Option<Duration>,
+    eviction_policy: EvictionPolicy,
+}
+
+impl ZCacheConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expiry time used for entries written without one.
+    pub fn default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.default_ttl = Some(default_ttl);
+        self
+    }
+
+    /// Shortest expiry time. Shorter ones are raised to it.
+    pub fn min_ttl(mut self, min_ttl: Duration) -> Self {
+        self.min_ttl = Some(min_ttl);
+        self
+    }
+
+    /// Longest expiry time. Longer ones are lowered to it, and entries
+    /// written without expiry expire after it.
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = Some(max_ttl);
+        self
+    }
+
+    /// Same as `set_max_entries`.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Starts the reaper with the given interval.
+    pub fn reaper_interval(mut self, reaper_interval: Duration) -> Self {
+        self.reaper_interval = Some(reaper_interval);
+        self
+    }
+
+    /// Eviction order used by the entries and memory limits, LRU by default.
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    // Applies the default TTL, then clamps the result into the TTL bounds.
+    fn effective_ttl(&self, expires_in: Option<Duration>) -> Option<Duration> {
+        match expires_in.or(self.default_ttl) {
+            Some(ttl) => {
+                let ttl = self.min_ttl.map_or(ttl, |min_ttl| ttl.max(min_ttl));
+                Some(self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl)))
+            }
+            None => self.max_ttl,
+        }
+    }
+}
+
+/// Boxed future returned by `Backend` methods.
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Slower shared cache tier, e.g. Redis, behind a cache created with
+/// `with_backend`.
+pub trait Backend<V, K = String>: Send + Sync {
+    /// Returns the value with its remaining TTL, `None` if it doesn't expire.
+    fn get<'a>(&'a self, key: &'a K) -> BackendFuture<'a, Option<(V, Option<Duration>)>>;
+
+    fn set<'a>(
+        &'a self,
+        key: &'a K,
+        value: V,
+        expires_in: Option<Duration>,
+    ) -> BackendFuture<'a, ()>;
+
+    fn delete<'a>(&'a self, key: &'a K) -> BackendFuture<'a, ()>;
+}
+
+/// In-memory `Backend`, useful for testing.
+pub struct MemoryBackend<V, K = String> {
+    entries: Mutex<HashMap<K, (V, u128)>>,
+}
+
+impl<V, K> MemoryBackend<V, K> {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<V, K> Default for MemoryBackend<V, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V, K> Backend<V, K> for MemoryBackend<V, K>
+where
+    V: Clone + Send + 'static,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    fn get<'a>(&'a self, key: &'a K) -> BackendFuture<'a, Option<(V, Option<Duration>)>> {
+        Box::pin(async move {
+            let entries = lock(&self.entries);
+            let (value, valid_until) = entries.get(key)?;
+            let now = now_in_millis();
+            let ttl = (*valid_until != 0).then(|| remaining(*valid_until, now));
+            is_valid(*valid_until, now).then(|| (value.clone(), ttl))
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a K,
+        value: V,
+        expires_in: Option<Duration>,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let valid_until = valid_until(expires_in, now_in_millis());
+            lock(&self.entries).insert(key.clone(), (value, valid_until));
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a K) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            lock(&self.entries).remove(key);
+        })
+    }
+}
+
+struct Store<K, V, S> {
+    shards: [Shard<K, V, S>; SHARD_COUNT],
+    hasher: S,
+    totals: Arc<StoreTotals<K, V>>,
+}
+
+impl<K: 'static, V: 'static, S: Clone> Store<K, V, S> {
+    fn new(hasher: S) -> Self {
+        Self::with_capacity(0, hasher)
+    }
+
+    // The capacity is split evenly between the shards.
+    fn with_capacity(capacity: usize, hasher: S) -> Self {
+        let capacity = capacity.div_ceil(SHARD_COUNT);
+        let totals = Arc::new(StoreTotals {
+            len: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            peak_bytes: AtomicUsize::new(0),
+            entry_size: default_entry_size().map(OnceLock::from).unwrap_or_default(),
+            ordered: AtomicBool::new(false),
+            order: Mutex::new(None),
+            age: AtomicU64::new(0),
+            evicting: Mutex::new(()),
+        });
+        Self {
+            shards: std::array::from_fn(|_| {
+                RwLock::new(ShardMap {
+                    map: HashMap::with_capacity_and_hasher(capacity, hasher.clone()),
+                    totals: totals.clone(),
+                })
+            }),
+            hasher,
+            totals,
+        }
+    }
+}
+
+impl<K, V, S> Store<K, V, S> {
+    fn iter(&self) -> std::slice::Iter<'_, Shard<K, V, S>> {
+        self.shards.iter()
+    }
+
+    fn bytes(&self) -> usize {
+        self.totals.bytes.load(Ordering::Relaxed)
+    }
+
+    fn peak_bytes(&self) -> &AtomicUsize {
+        &self.totals.peak_bytes
+    }
+
+    fn age(&self) -> u64 {
+        self.totals.age.load(Ordering::Relaxed)
+    }
+
+    // Entries stored before the size function was set are measured again.
+    fn measure(&self, entry_size: EntrySize<K, V>) {
+        if self.totals.entry_size.set(entry_size).is_ok() {
+            for shard in self.iter() {
+                write_shard(shard).measure();
+            }
+        }
+    }
+}
+
+// Totals of all the shards of a store, kept up to date by `ShardMap`.
+struct StoreTotals<K, V> {
+    len: AtomicUsize,
+    bytes: AtomicUsize,
+    // Highest `bytes` after a write, until reset with `reset_stats`.
+    peak_bytes: AtomicUsize,
+    // Sizes are counted only once it's known how to measure the entries.
+    entry_size: OnceLock<EntrySize<K, V>>,
+    // Whether `order` is kept, changed only with all the shards locked, so
+    // writers can check it without locking the order.
+    ordered: AtomicBool,
+    order: Mutex<Option<EvictionOrder<K>>>,
+    // LFU priority of the last evicted entry, added to the counts of reads.
+    age: AtomicU64,
+    // Held while evicting, so concurrent writers don't evict more than needed.
+    evicting: Mutex<()>,
+}
+
+impl<K, V> StoreTotals<K, V> {
+    fn is_ordered(&self) -> bool {
+        self.ordered.load(Ordering::Relaxed)
+    }
+
+    fn with_order<R, F: FnOnce(&mut EvictionOrder<K>) -> R>(&self, f: F) -> Option<R> {
+        if !self.is_ordered() {
+            return None;
+        }
+        lock(&self.order).as_mut().map(f)
+    }
+
+    fn size(&self, key: &K, value: &V) -> usize {
+        self.entry_size
+            .get()
+            .map_or(0, |entry_size| entry_size(key, value))
+    }
+
+    // The new size is added before the old one is subtracted, and the peak is
+    // taken from the final total, so replacing an entry never counts both.
+    // With a limit set, the peak is taken after evictions instead.
+    fn replaced(&self, added: usize, removed: usize) {
+        let total = self.bytes.fetch_add(added, Ordering::Relaxed) + added;
+        self.bytes.fetch_sub(removed, Ordering::Relaxed);
+        if !self.is_ordered() {
+            self.peak_bytes
+                .fetch_max(total.saturating_sub(removed), Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    policy: EvictionPolicy,
+}
+
+// Record of an entry taken from the eviction order.
+#[derive(Clone, Copy)]
+enum Popped {
+    Ranked((u64, u64)),
+    Expiring((u128, u64)),
+}
+
+// Keys of all the entries of a store, ordered by their rank and expiry, so
+// entries to evict are found without scanning the store. Reads don't lock the
+// order, so ranks of read entries are only updated once they're popped.
+struct EvictionOrder<K> {
+    limits: Limits,
+    by_rank: BTreeMap<(u64, u64), K>,
+    // Expiry with the version of the entry, which is unique.
+    by_expiry: BTreeMap<(u128, u64), K>,
+}
+
+impl<K: Clone> EvictionOrder<K> {
+    fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            by_rank: BTreeMap::new(),
+            by_expiry: BTreeMap::new(),
+        }
+    }
+
+    fn insert<V>(&mut self, key: &K, entry: &mut CacheEntry<V>) {
+        let rank = entry.rank(self.limits.policy);
+        self.by_rank.insert(rank, key.clone());
+        entry.ranked = Some(rank);
+        entry.expiring = (entry.valid_until != 0).then_some((entry.valid_until, entry.version));
+        if let Some(expiring) = entry.expiring {
+            self.by_expiry.insert(expiring, key.clone());
+        }
+    }
+
+    fn remove<V>(&mut self, entry: &CacheEntry<V>) {
+        if let Some(rank) = entry.ranked {
+            self.by_rank.remove(&rank);
+        }
+        if let Some(expiring) = entry.expiring {
+            self.by_expiry.remove(&expiring);
+        }
+    }
+
+    // Pops the next entry to evict while the store is over the limits,
+    // expired entries first.
+    fn pop(&mut self, len: usize, bytes: usize, now: u128) -> Option<(K, Popped)> {
+        let Limits {
+            max_entries,
+            max_bytes,
+            ..
+        } = self.limits;
+        let over =
+            max_entries.is_some_and(|max| len > max) || max_bytes.is_some_and(|max| bytes > max);
+        if !over {
+            return None;
+        }
+        match self.by_expiry.first_entry() {
+            Some(first) if first.key().0 <= now => {
+                let (expiring, key) = first.remove_entry();
+                Some((key, Popped::Expiring(expiring)))
+            }
+            _ => {
+                let (rank, key) = self.by_rank.pop_first()?;
+                Some((key, Popped::Ranked(rank)))
+            }
+        }
+    }
+}
+
+// Entries of `ZEntry` caches with `String` keys are measured from the start,
+// so `peak_bytes` is tracked without a memory limit. Other types are
+// measured once `set_max_bytes` or `estimated_bytes` is called.
+fn default_entry_size<K: 'static, V: 'static>() -> Option<EntrySize<K, V>> {
+    fn size<K: 'static, V: 'static>(key: &K, value: &V) -> usize {
+        let key: &dyn Any = key;
+        let value: &dyn Any = value;
+        match (key.downcast_ref::<String>(), value.downcast_ref::<ZEntry>()) {
+            (Some(key), Some(value)) => entry_size(key, value),
+            _ => 0,
+        }
+    }
+    let measured =
+        TypeId::of::<K>() == TypeId::of::<String>() && TypeId::of::<V>() == TypeId::of::<ZEntry>();
+    measured.then_some(size::<K, V> as EntrySize<K, V>)
+}
+
+// Map of a single shard. Reads go through `Deref`, while all the changes go
+// through its methods, so the store totals can't drift from the entries.
+struct ShardMap<K, V, S> {
+    map: HashMap<K, CacheEntry<V>, S>,
+    totals: Arc<StoreTotals<K, V>>,
+}
+
+impl<K, V, S> Deref for ShardMap<K, V, S> {
+    type Target = HashMap<K, CacheEntry<V>, S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl<K: Eq + Hash + Clone, V, S: BuildHasher> ShardMap<K, V, S> {
+    fn insert(&mut self, key: K, mut entry: CacheEntry<V>) -> Option<CacheEntry<V>> {
+        let totals = &self.totals;
+        entry.size = totals.size(&key, &entry.value);
+        let size = entry.size;
+        let priority = entry.priority.get_mut();
+        *priority = (*priority).max(totals.age.load(Ordering::Relaxed));
+        totals.with_order(|order| {
+            if let Some(previous) = self.map.get(&key) {
+                order.remove(previous);
+            }
+            order.insert(&key, &mut entry);
+        });
+        let previous = self.map.insert(key, entry);
+        let replaced = match &previous {
+            Some(previous) => previous.size,
+            None => {
+                totals.len.fetch_add(1, Ordering::Relaxed);
+                0
+            }
+        };
+        totals.replaced(size, replaced);
+        previous
+    }
+
+    // Values can be modified in place, but their size must be updated with
+    // `resize` afterwards, and their expiry changed with `set_valid_until`.
+    fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut CacheEntry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.get_mut(key)
+    }
+
+    fn resize<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let totals = &self.totals;
+        let Some((stored, entry)) = self.map.get_key_value(key) else {
+            return;
+        };
+        let size = totals.size(stored, &entry.value);
+        totals.replaced(size, entry.size);
+        if let Some(entry) = self.map.get_mut(key) {
+            entry.size = size;
+        }
+    }
+
+    fn set_valid_until<Q>(&mut self, key: &Q, valid_until: u128)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let totals = &self.totals;
+        let stored = match totals.is_ordered() {
+            true => self
+                .map
+                .get_key_value(key)
+                .map(|(stored, _)| stored.clone()),
+            false => None,
+        };
+        let Some(entry) = self.map.get_mut(key) else {
+            return;
+        };
+        entry.valid_until = valid_until;
+        if let Some(stored) = stored {
+            totals.with_order(|order| {
+                order.remove(entry);
+                order.insert(&stored, entry);
+            });
+        }
+    }
+
+    fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, CacheEntry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let removed = self.map.remove_entry(key);
+        if let Some((_, entry)) = &removed {
+            self.removed(entry);
+        }
+        removed
+    }
+
+    // Removes an entry popped from the eviction order. Entries read since
+    // they were ranked are ranked again instead, and records of entries
+    // changed in the meantime are skipped.
+    fn evict(&mut self, key: &K, popped: Popped) -> Option<(K, CacheEntry<V>)> {
+        let totals = &self.totals;
+        let entry = self.map.get_mut(key)?;
+        let mut guard = lock(&totals.order);
+        let order = guard.as_mut()?;
+        match popped {
+            Popped::Ranked(rank) if entry.ranked == Some(rank) => {
+                let current = entry.rank(order.limits.policy);
+                if current > rank {
+                    order.by_rank.insert(current, key.clone());
+                    entry.ranked = Some(current);
+                    return None;
+                }
+                if order.limits.policy == EvictionPolicy::Lfu {
+                    let priority = entry.priority.load(Ordering::Relaxed);
+                    totals.age.fetch_max(priority, Ordering::Relaxed);
+                }
+            }
+            Popped::Expiring(expiring) if entry.expiring == Some(expiring) => {}
+            _ => return None,
+        }
+        drop(guard);
+        self.remove_entry(key)
+    }
+
+    fn drain(&mut self) -> Removed<K, V> {
+        let removed: Removed<K, V> = self.map.drain().collect();
+        for (_, entry) in &removed {
+            self.removed(entry);
+        }
+        removed
+    }
+
+    fn removed(&self, entry: &CacheEntry<V>) {
+        let totals = &self.totals;
+        totals.len.fetch_sub(1, Ordering::Relaxed);
+        totals.bytes.fetch_sub(entry.size, Ordering::Relaxed);
+        totals.with_order(|order| order.remove(entry));
+    }
+
+    // Puts the entry back into the eviction order, with its current rank.
+    fn rerank(&mut self, key: &K) {
+        let totals = &self.totals;
+        if let Some(entry) = self.map.get_mut(key) {
+            totals.with_order(|order| {
+                order.remove(entry);
+                order.insert(key, entry);
+            });
+        }
+    }
+}
+
+impl<K, V, S> ShardMap<K, V, S> {
+    fn measure(&mut self) {
+        let totals = &self.totals;
+        for (key, entry) in self.map.iter_mut() {
+            let size = totals.size(key, &entry.value);
+            totals.replaced(size, entry.size);
+            entry.size = size;
+        }
+    }
+}
+
+impl<K, V, S: BuildHasher> Store<K, V, S> {
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &Shard<K, V, S> {
+        &self.shards[self.index(key)]
+    }
+
+    // Shard maps pick buckets by the low bits of the same hash, so the shard is
+    // picked by the high ones to keep keys spread within shards.
+    fn index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        (self.hasher.hash_one(key) >> 32) as usize % SHARD_COUNT
+    }
+}
+
+impl<K: Eq + Hash + Clone, V, S: BuildHasher> Store<K, V, S> {
+    // The eviction order is kept only while a limit is set, and built again
+    // from all the entries once one is.
+    fn set_limits(&self, shards: &mut [RwLockWriteGuard<'_, ShardMap<K, V, S>>], limits: Limits) {
+        let totals = &self.totals;
+        let limited = limits.max_entries.is_some() || limits.max_bytes.is_some();
+        let mut order = lock(&totals.order);
+        *order = match order.take() {
+            _ if !limited => None,
+            Some(mut order) if order.limits.policy == limits.policy => {
+                order.limits = limits;
+                Some(order)
+            }
+            _ => {
+                let mut order = EvictionOrder::new(limits);
+                for shard in shards.iter_mut() {
+                    for (key, entry) in shard.map.iter_mut() {
+                        order.insert(key, entry);
+                    }
+                }
+                Some(order)
+            }
+        };
+        totals.ordered.store(limited, Ordering::Relaxed);
+    }
+
+    // Evicts entries until the store is back within its limits, except for
+    // the key just inserted. Only the shards of the evicted entries are
+    // locked, one at a time.
+    fn evict(&self, inserted: Option<&K>, now: u128) -> Removed<K, V> {
+        let totals = &self.totals;
+        let mut evicted = Vec::new();
+        if !totals.is_ordered() {
+            return evicted;
+        }
+        let _evicting = lock(&totals.evicting);
+        let mut spared = false;
+        while let Some((key, popped)) = totals
+            .with_order(|order| order.pop(totals.len.load(Ordering::Relaxed), self.bytes(), now))
+            .flatten()
+        {
+            if inserted == Some(&key) {
+                spared = true;
+                continue;
+            }
+            if let Some(victim) = write_shard(self.shard(&key)).evict(&key, popped) {
+                evicted.push(victim);
+            }
+        }
+        if let Some(key) = inserted.filter(|_| spared) {
+            write_shard(self.shard(key)).rerank(key);
+        }
+        totals.peak_bytes.fetch_max(self.bytes(), Ordering::Relaxed);
+        evicted
+    }
+}
+
+/// Cache of `V` values with its own store, configuration and stats,
+/// independent of the global one used by `ZCache`.
+///
+/// Keys are `String`s by default, but any `K: Eq + Hash + Clone` type can be
+/// used, also implementing `Debug` with the `tracing` feature. Methods take
+/// keys by reference, e.g. `&str` for `String` keys. Methods whose errors name
+/// the key, like `fetch`, need keys implementing `Display`. Key prefixes,
+/// namespaces and the memory limit are only supported for `String` keys.
+///
+/// Keys are hashed with `S`, SipHash by default, which resists collision
+/// attacks from untrusted keys. Faster hashers can be used with `with_hasher`
+/// if keys are trusted.
+pub struct Cache<V, K = String, S = RandomState> {
+    store: ZCacheStore<K, V, S>,
+    config: Mutex<ZCacheConfig>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    // Shared with the reaper task.
+    expirations: Arc<AtomicU64>,
+    in_flight: InFlightLocks<K>,
+    computing: ComputingKeys<K>,
+    reaper: ReaperSlot,
+    clock: SharedClock,
+    tags: TagIndex<K>,
+    // Expiry of cached `None` results of `fetch_with_negative`, by key.
+    negative: NegativeKeys<K>,
+    // Circuit breakers of `fetch_with_breaker`, by key.
+    breakers: Breakers<K>,
+    on_evict: EvictSlot<K, V>,
+    byte_limit: Mutex<Option<usize>>,
+    entry_limit: Arc<EntryLimit<V>>,
+    backend: Option<Arc<dyn Backend<V, K>>>,
+    backend_pending: PendingKeys<K>,
+    // State of the random generator used by `write_jittered`.
+    jitter: Mutex<u64>,
+    // Hits and misses of reads through `Namespace` handles, by namespace.
+    namespace_stats: NamespaceStats,
+    // Snapshot path saved by `flush`.
+    #[cfg(feature = "serde")]
+    persist_path: Mutex<Option<PathBuf>>,
+    events: broadcast::Sender<CacheEvent<K>>,
+}
+
+pub type ZCacheInstance = Cache<ZEntry>;
+
+impl<V: 'static, K: Clone + 'static, S: Clone + Default> Default for Cache<V, K, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<V: 'static, K: Clone + 'static, S: Clone> Cache<V, K, S> {
+    /// Creates a cache hashing keys with the given hasher.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            store: Arc::new(Store::new(hasher)),
+            config: Mutex::new(ZCacheConfig::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: Default::default(),
+            in_flight: Default::default(),
+            computing: Default::default(),
+            reaper: Default::default(),
+            clock: Arc::new(RwLock::new(Arc::new(SystemClock))),
+            tags: Default::default(),
+            negative: Default::default(),
+            breakers: Default::default(),
+            on_evict: Default::default(),
+            byte_limit: Mutex::new(None),
+            entry_limit: Arc::new(EntryLimit {
+                limit: Mutex::new(None),
+                rejections: AtomicU64::new(0),
+            }),
+            backend: None,
+            backend_pending: Default::default(),
+            jitter: Mutex::new(RandomState::new().hash_one(0)),
+            namespace_stats: Default::default(),
+            #[cfg(feature = "serde")]
+            persist_path: Mutex::new(None),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl<V: Clone + Send + 'static, K: Eq + Hash + Clone + KeyDebug + Send + Sync + 'static>
+    Cache<V, K>
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let cache = Self::default();
+        cache.set_clock(clock);
+        cache
+    }
+
+    /// Creates a cache with room for at least `capacity` entries, so the store
+    /// doesn't resize while filling up. Keys are spread over multiple shards,
+    /// so if they're distributed unevenly, some shards might still resize.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            store: Arc::new(Store::with_capacity(capacity, RandomState::new())),
+            ..Self::default()
+        }
+    }
+
+    /// Creates a cache backed by a slower shared tier. Async writes go to
+    /// both tiers. On local misses, `read_through`, `fetch` and `try_fetch`,
+    /// and the variants built on them, consult the backend before running the
+    /// loader, caching the values for their remaining TTL there.
+    /// `fetch_swr`, `fetch_refresh_ahead` and `fetch_sync` don't. Sync
+    /// methods, like `increment`, `update`, `touch`, `expire_now` and deletes,
+    /// update the backend from a spawned task when called within a tokio
+    /// runtime. Until it's done, the keys aren't read from the backend.
+    /// Loading snapshots writes only the local tier.
+    pub fn with_backend(backend: Arc<dyn Backend<V, K>>) -> Self {
+        Self {
+            backend: Some(backend),
+            ..Self::default()
+        }
+    }
+}
+
+impl<V, K, S> Cache<V, K, S>
+where
+    V: Clone + Send + 'static,
+    K: Eq + Hash + Clone + KeyDebug + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    /// Replaces the clock used for expiry checks.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.write().unwrap_or_else(PoisonError::into_inner) = clock;
+    }
+
+    /// Concurrent calls for the same missing key run only a single `f` at a
+    /// time. Others wait for it to finish and reuse the cached result, or run
+    /// their own `f` if it didn't produce a value.
+    pub async fn fetch<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        if let Some(value) = self.read(key) {
+            return Ok(value);
+        }
+        self.load(key, expires_in, f).await
+    }
+
+    // The part of `fetch` after a missed read.
+    async fn load<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        let _in_flight = InFlightGuard::acquire(&self.in_flight, key.to_owned()).await;
+        if let Some(value) = self.lookup(key) {
+            return Ok(V::clone(&value));
+        }
+        match self.read_backend(key).await {
+            Some(value) => Ok(value),
+            None => match f().await {
+                Some(value) => {
+                    self.try_write(key, value.clone(), expires_in).await?;
+                    Ok(value)
+                }
+                None => Err(ZCacheError::FetchError(key.to_string())),
+            },
+        }
+    }
+
+    /// Same as `fetch`, but `f` can fail with an error, which is returned to
+    /// the caller unchanged. Errors are not cached. Values over the
+    /// `set_max_entry_bytes` limit fail with `EntryTooLarge`, converted into
+    /// `E`.
+    pub async fn try_fetch<F, Fut, E, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+        E: From<ZCacheError>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(value) = self.read(key) {
+            return Ok(value);
+        }
+
+        let _in_flight = InFlightGuard::acquire(&self.in_flight, key.to_owned()).await;
+        if let Some(value) = self.lookup(key) {
+            return Ok(V::clone(&value));
+        }
+        if let Some(value) = self.read_backend(key).await {
+            return Ok(value);
+        }
+        let value = f().await?;
+        self.try_write(key, value.clone(), expires_in).await?;
+        Ok(value)
+    }
+
+    /// Same as `fetch`, but if `f` returns `None`, it's called again up to
+    /// `retries` times, sleeping for `backoff` between attempts. The first
+    /// value returned is cached.
+    pub async fn fetch_retry<Q, F, Fut>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        retries: usize,
+        backoff: Duration,
+        mut f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Option<V>>,
+    {
+        self.fetch(key, expires_in, || async move {
+            for attempt in 0..=retries {
+                if attempt > 0 {
+                    tokio::time::sleep(backoff).await;
+                }
+                if let Some(value) = f().await {
+                    return Some(value);
+                }
+            }
+            None
+        })
+        .await
+    }
+
+    /// Same as `fetch`, but fails with `Timeout` if `f` doesn't complete within
+    /// `timeout`. Nothing is written on timeout.
+    pub async fn fetch_timeout<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        timeout: Duration,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        self.try_fetch(key, expires_in, || async {
+            match tokio::time::timeout(timeout, f()).await {
+                Ok(Some(value)) => Ok(value),
+                Ok(None) => Err(ZCacheError::FetchError(key.to_string())),
+                Err(_) => Err(ZCacheError::Timeout(key.to_string())),
+            }
+        })
+        .await
+    }
+
+    /// Same as `fetch`, but if `f` returns `None`, the absence of the key is
+    /// cached for `negative_ttl`. Until then, calls fail with `FetchError`
+    /// without running `f`. `read` still returns `None` for such keys.
+    /// Expired misses are dropped by the reaper and `purge_expired`.
+    pub async fn fetch_with_negative<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        negative_ttl: Duration,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        if let Some(value) = self.read(key) {
+            return Ok(value);
+        }
+        if self.is_known_absent(key) {
+            return Err(ZCacheError::FetchError(key.to_string()));
+        }
+
+        let _in_flight = InFlightGuard::acquire(&self.in_flight, key.to_owned()).await;
+        if let Some(value) = self.lookup(key) {
+            return Ok(V::clone(&value));
+        }
+        if self.is_known_absent(key) {
+            return Err(ZCacheError::FetchError(key.to_string()));
+        }
+        if let Some(value) = self.read_backend(key).await {
+            return Ok(value);
+        }
+        match f().await {
+            Some(value) => {
+                self.try_write(key, value.clone(), expires_in).await?;
+                Ok(value)
+            }
+            None => {
+                let valid_until = valid_until(Some(negative_ttl), self.now());
+                lock(&self.negative).insert(key.to_owned(), valid_until);
+                Err(ZCacheError::FetchError(key.to_string()))
+            }
+        }
+    }
+
+    /// Same as `fetch`, but if `f` returns `None`, falls back to an expired
+    /// entry still kept in the store. Stale values can be arbitrarily old, as
+    /// they're kept only until removed by the reaper, `purge_expired` or the
+    /// limits.
+    pub async fn fetch_or_stale<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        match self.fetch(key, expires_in, f).await {
+            Err(error) => read_shard(self.shard(key))
+                .get(key)
+                .map(|entry| V::clone(&entry.value))
+                .ok_or(error),
+            result => result,
+        }
+    }
+
+    /// Same as `fetch`, but with a circuit breaker around `f`. After
+    /// `failure_threshold` consecutive `None` results for a key, calls fail
+    /// with `CircuitOpen` without running `f` until the cooldown passes. The
+    /// next call then probes `f` once, closing the breaker on success or
+    /// reopening it for another cooldown on failure. Cached values are
+    /// returned regardless of the breaker. The reaper and `purge_expired`
+    /// forget breakers of keys without a failure for a whole cooldown.
+    pub async fn fetch_with_breaker<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        breaker: BreakerConfig,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        if let Some(value) = self.read(key) {
+            return Ok(value);
+        }
+        if self.is_circuit_open(key, breaker) {
+            return Err(ZCacheError::CircuitOpen(key.to_string()));
+        }
+        self.load(key, expires_in, || async {
+            let value = f().await;
+            let mut breakers = lock(&self.breakers);
+            match value {
+                Some(_) => {
+                    breakers.remove(key);
+                }
+                None => {
+                    let state = breakers.entry(key.to_owned()).or_default();
+                    let cooldown_end = self.now() + breaker.cooldown.as_millis();
+                    state.failures += 1;
+                    state.forget_at = cooldown_end;
+                    if state.failures >= breaker.failure_threshold {
+                        state.open_until = cooldown_end;
+                    }
+                }
+            }
+            value
+        })
+        .await
+    }
+
+    // Once the cooldown passes, lets a single call through to probe the
+    // loader, and keeps failing others until the probe completes.
+    fn is_circuit_open<Q>(&self, key: &Q, breaker: BreakerConfig) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let mut breakers = lock(&self.breakers);
+        match breakers.get_mut(key) {
+            Some(state) if state.failures >= breaker.failure_threshold => {
+                if now < state.open_until {
+                    return true;
+                }
+                state.open_until = now + breaker.cooldown.as_millis();
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn is_known_absent<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let mut negative = lock(&self.negative);
+        match negative.get(key) {
+            Some(&valid_until) if is_valid(valid_until, now) => true,
+            Some(_) => {
+                negative.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Stale-while-revalidate version of `fetch`. Entries younger than
+    /// `fresh_for` are served from the cache. Older entries are served stale
+    /// for another `stale_for`, while a background task refreshes them with
+    /// `f`. It blocks on `f` only if the entry is missing or expired. Must be
+    /// called from within a tokio runtime.
+    pub async fn fetch_swr<F, Fut, Q>(
+        &self,
+        key: &Q,
+        fresh_for: Duration,
+        stale_for: Duration,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Option<V>> + Send + 'static,
+        V: Sync,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        let (fresh_for, stale_for) = self.clamp_window(fresh_for, stale_for);
+        let now = self.now();
+        let cached = read_shard(self.shard(key)).get(key).and_then(|entry| {
+            let value = entry.access(now, self.store.age())?;
+            let refresh = entry.is_stale(now) && !entry.refreshing.swap(true, Ordering::AcqRel);
+            Some((value, refresh))
+        });
+        if let Some((value, refresh)) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            if refresh {
+                self.spawn_refresh(key.to_owned(), fresh_for, stale_for, f);
+            }
+            return Ok(V::clone(&value));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let _in_flight = InFlightGuard::acquire(&self.in_flight, key.to_owned()).await;
+        if let Some(value) = self.lookup(key) {
+            return Ok(V::clone(&value));
+        }
+        match f().await {
+            Some(value) => {
+                self.check_size(&value)?;
+                let entry =
+                    CacheEntry::with_stale_window(value.clone(), fresh_for, stale_for, self.now());
+                self.insert_entry(key.to_owned(), entry);
+                self.write_backend(key, &value, Some(fresh_for + stale_for))
+                    .await;
+                Ok(value)
+            }
+            None => Err(ZCacheError::FetchError(key.to_string())),
+        }
+    }
+
+    // The refreshed value replaces the entry only if it's still in the store,
+    // so a background refresh never bypasses the entries limit.
+    fn spawn_refresh<F, Fut>(&self, key: K, fresh_for: Duration, stale_for: Duration, f: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Option<V>> + Send + 'static,
+        V: Sync,
+    {
+        let store = self.store.clone();
+        let clock = self.clock.clone();
+        let entry_limit = self.entry_limit.clone();
+        let backend = self.backend.clone();
+        tokio::spawn(async move {
+            let value = f().await;
+            let refreshed = refresh_entry(
+                &store,
+                &clock,
+                &entry_limit,
+                &key,
+                value,
+                fresh_for,
+                stale_for,
+            );
+            if let (Some(backend), Some(value)) = (backend, refreshed) {
+                backend
+                    .set(&key, V::clone(&value), Some(fresh_for + stale_for))
+                    .await;
+            }
+        });
+    }
+
+    /// Same as `fetch`, but entries are reloaded with `f` in the background
+    /// once read within `refresh_window` of their expiry, so hot keys don't
+    /// expire. Only a single refresh per key runs at a time, and reads keep
+    /// returning the current value until it completes. Must be called from
+    /// within a tokio runtime.
+    pub async fn fetch_refresh_ahead<Q, F, Fut>(
+        &self,
+        key: &Q,
+        expires_in: Duration,
+        refresh_window: Duration,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<V>> + Send + 'static,
+        V: Sync,
+    {
+        if let Some(value) = self.read(key) {
+            return Ok(value);
+        }
+
+        let _in_flight = InFlightGuard::acquire(&self.in_flight, key.to_owned()).await;
+        if let Some(value) = self.lookup(key) {
+            return Ok(V::clone(&value));
+        }
+        let fresh_for = expires_in.saturating_sub(refresh_window);
+        let (fresh_for, refresh_window) = self.clamp_window(fresh_for, refresh_window);
+        match f().await {
+            Some(value) => {
+                self.check_size(&value)?;
+                let mut entry = CacheEntry::with_stale_window(
+                    value.clone(),
+                    fresh_for,
+                    refresh_window,
+                    self.now(),
+                );
+                entry.refresher =
+                    Some(self.refresher(key.to_owned(), fresh_for, refresh_window, f));
+                self.insert_entry(key.to_owned(), entry);
+                self.write_backend(key, &value, Some(fresh_for + refresh_window))
+                    .await;
+                Ok(value)
+            }
+            None => Err(ZCacheError::FetchError(key.to_string())),
+        }
+    }
+
+    // Clamps the whole lifetime of entries with a stale window into the TTL
+    // bounds, keeping as much of the fresh part as fits.
+    fn clamp_window(&self, fresh_for: Duration, stale_for: Duration) -> (Duration, Duration) {
+        let expires_in = fresh_for.saturating_add(stale_for);
+        let expires_in = lock(&self.config)
+            .effective_ttl(Some(expires_in))
+            .unwrap_or(expires_in);
+        let fresh_for = fresh_for.min(expires_in);
+        (fresh_for, expires_in - fresh_for)
+    }
+
+    // The refresher holds a weak reference to the store, because the store
+    // holds the refresher.
+    fn refresher<F, Fut>(&self, key: K, fresh_for: Duration, stale_for: Duration, f: F) -> Refresher
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<V>> + Send + 'static,
+        V: Sync,
+    {
+        let store = Arc::downgrade(&self.store);
+        let clock = self.clock.clone();
+        let entry_limit = self.entry_limit.clone();
+        let backend = self.backend.clone();
+        let f = Arc::new(f);
+        Arc::new(move || {
+            let (store, clock, key, f) = (store.clone(), clock.clone(), key.clone(), f.clone());
+            let (entry_limit, backend) = (entry_limit.clone(), backend.clone());
+            tokio::spawn(async move {
+                let value = f().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                let refreshed = refresh_entry(
+                    &store,
+                    &clock,
+                    &entry_limit,
+                    &key,
+                    value,
+                    fresh_for,
+                    stale_for,
+                );
+                if let (Some(backend), Some(value)) = (backend, refreshed) {
+                    backend
+                        .set(&key, V::clone(&value), Some(fresh_for + stale_for))
+                        .await;
+                }
+            });
+        })
+    }
+
+    /// Synchronous version of `fetch`. It doesn't deduplicate concurrent
+    /// calls for the same key.
+    pub fn fetch_sync<F, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Option<V>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        match self.read(key) {
+            Some(value) => Ok(value),
+            None => match f() {
+                Some(value) => {
+                    let entry = self.cache_entry(expires_in, value.clone())?;
+                    self.set_backend(key, &entry, self.now());
+                    self.insert_entry(key.to_owned(), entry);
+                    Ok(value)
+                }
+                None => Err(ZCacheError::FetchError(key.to_string())),
+            },
+        }
+    }
+
+    /// Returns a valid entry, or caches the value computed by `f` on a miss.
+    /// Concurrent calls for the same key block until the first one computes
+    /// it, so `f` runs once, while other keys are computed in parallel.
+    /// No cache lock is held while `f` runs. Values over the
+    /// `set_max_entry_bytes` limit are returned but not cached.
+    pub fn get_or_compute<F, Q>(&self, key: &Q, expires_in: Option<Duration>, f: F) -> V
+    where
+        F: FnOnce() -> V,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(value) = self.read(key) {
+            return value;
+        }
+        let _computing = ComputeGuard::acquire(&self.computing, key.to_owned());
+        // Checked again without counting another miss.
+        if let Some(value) = self.lookup(key) {
+            return V::clone(&value);
+        }
+        let value = f();
+        if let Ok(entry) = self.cache_entry(expires_in, value.clone()) {
+            self.set_backend(key, &entry, self.now());
+            self.insert_entry(key.to_owned(), entry);
+        }
+        value
+    }
+
+    pub fn read<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        self.read_arc(key).map(|value| V::clone(&value))
+    }
+
+    /// Returns a valid entry like `read`, but without counting it as a use.
+    /// It doesn't update the entry access time and count used for eviction,
+    /// nor the hit and miss stats, so inspecting the cache doesn't affect it.
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let cache = read_shard(self.shard(key));
+        cache
+            .get(key)
+            .filter(|entry| entry.is_valid(now))
+            .map(|entry| V::clone(&entry.value))
+    }
+
+    /// Same as `read`, but on a miss consults the backend. Values found there
+    /// are cached locally with the default TTL.
+    pub async fn read_through<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.read(key) {
+            Some(value) => Some(value),
+            None => self.read_backend(key).await,
+        }
+    }
+
+    async fn read_backend<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let backend = self.backend.as_ref()?;
+        // The backend copy is being deleted or replaced.
+        if lock(&self.backend_pending).contains_key(key) {
+            return None;
+        }
+        let key = key.to_owned();
+        let (value, ttl) = backend.get(&key).await?;
+        if let Ok(entry) = self.cache_entry(ttl, value.clone()) {
+            self.insert_entry(key, entry);
+        }
+        Some(value)
+    }
+
+    // Sync methods can't wait for the backend, so they update it from a
+    // spawned task. Without a runtime, only the local tier is updated.
+    fn spawn_backend(&self, deleted: Vec<K>, written: Option<(K, V, Option<Duration>)>) {
+        let Some(backend) = self.backend.clone() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let keys: Vec<K> = deleted
+            .iter()
+            .chain(written.iter().map(|(key, _, _)| key))
+            .cloned()
+            .collect();
+        let pending = self.backend_pending.clone();
+        for key in &keys {
+            *lock(&pending).entry(key.clone()).or_default() += 1;
+        }
+        runtime.spawn(async move {
+            for key in &deleted {
+                backend.delete(key).await;
+            }
+            if let Some((key, value, expires_in)) = written {
+                backend.set(&key, value, expires_in).await;
+            }
+            let mut pending = lock(&pending);
+            for key in keys {
+                if let Some(count) = pending.get_mut(&key) {
+                    *count -= 1;
+                    if *count == 0 {
+                        pending.remove(&key);
+                    }
+                }
+            }
+        });
+    }
+
+    fn delete_backend<I: IntoIterator<Item = K>>(&self, keys: I) {
+        if self.backend.is_some() {
+            self.spawn_backend(keys.into_iter().collect(), None);
+        }
+    }
+
+    // Copies an entry written by a sync method to the backend, with its
+    // remaining TTL.
+    fn set_backend<Q>(&self, key: &Q, entry: &CacheEntry<V>, now: u128)
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        if self.backend.is_some() {
+            let ttl = (entry.valid_until != 0).then(|| remaining(entry.valid_until, now));
+            let written = (key.to_owned(), V::clone(&entry.value), ttl);
+            self.spawn_backend(Vec::new(), Some(written));
+        }
+    }
+
+    async fn write_backend<Q>(&self, key: &Q, value: &V, expires_in: Option<Duration>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(backend) = &self.backend {
+            let expires_in = lock(&self.config).effective_ttl(expires_in);
+            backend
+                .set(&key.to_owned(), value.clone(), expires_in)
+                .await;
+        }
+    }
+
+    /// Returns `default` if the key is missing or expired. The default is not
+    /// written to the cache.
+    pub fn read_or<Q>(&self, key: &Q, default: V) -> V
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        self.read(key).unwrap_or(default)
+    }
+
+    /// Returns the result of `f` if the key is missing or expired. The result
+    /// is not written to the cache.
+    pub fn read_or_else<F: FnOnce() -> V, Q>(&self, key: &Q, f: F) -> V
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        self.read(key).unwrap_or_else(f)
+    }
+
+    /// Same as `read`, but returns a shared pointer to the stored value
+    /// instead of cloning it.
+    pub fn read_arc<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let result = self.lookup(key);
+        match result {
+            Some(_) => {
+                debug_event!(op = "read", key = ?key, outcome = "hit");
+                self.hits.fetch_add(1, Ordering::Relaxed)
+            }
+            None => {
+                debug_event!(op = "read", key = ?key, outcome = "miss");
+                self.misses.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+        result
+    }
+
+    fn lookup<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let cache = read_shard(self.shard(key));
+        let entry = cache.get(key)?;
+        let value = entry.access(now, self.store.age())?;
+        if let Some(refresher) = &entry.refresher {
+            if entry.is_stale(now)
+                && tokio::runtime::Handle::try_current().is_ok()
+                && !entry.refreshing.swap(true, Ordering::AcqRel)
+            {
+                refresher();
+            }
+        }
+        Some(value)
+    }
+
+    /// Reads multiple keys at once, locking the store only once. Results are
+    /// in the same order as `keys`.
+    pub fn mget<Q>(&self, keys: &[&Q]) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let shards = read_all(&self.store);
+        keys.iter()
+            .map(|key| {
+                let result = shards[self.store.index(key)]
+                    .get(*key)
+                    .and_then(|entry| entry.access(now, self.store.age()))
+                    .map(|value| V::clone(&value));
+                match result {
+                    Some(_) => {
+                        debug_event!(op = "read", key = ?key, outcome = "hit");
+                        self.hits.fetch_add(1, Ordering::Relaxed)
+                    }
+                    None => {
+                        debug_event!(op = "read", key = ?key, outcome = "miss");
+                        self.misses.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+                result
+            })
+            .collect()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let cache = read_shard(self.shard(key));
+        match cache.get(key) {
+            Some(entry) => entry.is_valid(now),
+            None => false,
+        }
+    }
+
+    /// Returns all keys holding a valid entry. Ordering is unspecified.
+    pub fn keys(&self) -> Vec<K> {
+        let now = self.now();
+        self.store
+            .iter()
+            .flat_map(|shard| {
+                read_shard(shard)
+                    .iter()
+                    .filter(|(_, entry)| entry.is_valid(now))
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Counts valid entries. Expired entries still kept in the store are skipped.
+    pub fn len(&self) -> usize {
+        let now = self.now();
+        self.store
+            .iter()
+            .map(|shard| {
+                read_shard(shard)
+                    .values()
+                    .filter(|entry| entry.is_valid(now))
+                    .count()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries the store can hold without resizing.
+    pub fn capacity(&self) -> usize {
+        self.store
+            .iter()
+            .map(|shard| read_shard(shard).capacity())
+            .sum()
+    }
+
+    /// Returns the remaining lifetime of a valid entry. Entries without
+    /// expiry report `Duration::MAX`.
+    pub fn ttl<Q>(&self, key: &Q) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let cache = read_shard(self.shard(key));
+        let valid_until = cache.get(key)?.valid_until;
+        if valid_until == 0 {
+            return Some(Duration::MAX);
+        }
+        let now = self.now();
+        if valid_until > now {
+            Some(remaining(valid_until, now))
+        } else {
+            None
+        }
+    }
+
+    /// Returns all valid entries with their remaining TTL, `None` for entries
+    /// without expiry, locking the store only once. Ordering is unspecified.
+    pub fn snapshot_entries(&self) -> Vec<(K, V, Option<Duration>)> {
+        let now = self.now();
+        read_all(&self.store)
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(_, entry)| entry.is_valid(now))
+            .map(|(key, entry)| {
+                let ttl = (entry.valid_until != 0).then(|| remaining(entry.valid_until, now));
+                (key.clone(), V::clone(&entry.value), ttl)
+            })
+            .collect()
+    }
+
+    /// Returns the values of all valid entries, without their expiry. Unlike
+    /// snapshots, it doesn't require the `serde` feature.
+    pub fn export(&self) -> HashMap<K, V> {
+        let now = self.now();
+        read_all(&self.store)
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(_, entry)| entry.is_valid(now))
+            .map(|(key, entry)| (key.clone(), V::clone(&entry.value)))
+            .collect()
+    }
+
+    /// Writes all the values of `map` with the same expiry, locking the store
+    /// only once.
+    pub async fn import(&self, map: HashMap<K, V>, expires_in: Option<Duration>) {
+        let entries = map
+            .into_iter()
+            .map(|(key, value)| (key, value, expires_in))
+            .collect();
+        self.mset(entries).await;
+    }
+
+    /// Returns the timestamps of a valid entry. It doesn't count as a read.
+    pub fn entry_info<Q>(&self, key: &Q) -> Option<EntryInfo>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let cache = read_shard(self.shard(key));
+        let entry = cache.get(key).filter(|entry| entry.is_valid(now))?;
+        Some(EntryInfo {
+            created_at: entry.created_at,
+            last_accessed: entry.last_accessed.load(Ordering::Relaxed) as u128,
+            valid_until: Some(entry.valid_until).filter(|valid_until| *valid_until != 0),
+        })
+    }
+
+    /// Writes a value, replacing the previous entry of the key.
+    ///
+    /// With a `set_max_entry_bytes` limit, larger values are silently
+    /// dropped: nothing is written, the previous entry is kept, and the value
+    /// is only counted in `stats().rejections`. Use `try_write` to get an
+    /// `EntryTooLarge` error for them instead.
+    pub async fn write<Q>(&self, key: &Q, value: V, expires_in: Option<Duration>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let _ = self.try_write(key, value, expires_in).await;
+    }
+
+    /// Same as `write`, but fails with `EntryTooLarge` if the value is larger
+    /// than the `set_max_entry_bytes` limit.
+    pub async fn try_write<Q>(
+        &self,
+        key: &Q,
+        value: V,
+        expires_in: Option<Duration>,
+    ) -> Result<(), ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let entry = self.cache_entry(expires_in, value)?;
+        self.write_backend(key, &entry.value, expires_in).await;
+        self.insert_entry(key.to_owned(), entry);
+        Ok(())
+    }
+
+    fn check_size(&self, value: &V) -> Result<(), ZCacheError> {
+        self.entry_limit.check(value)
+    }
+
+    /// Writes an entry expiring at the given time, clamped into the
+    /// configured TTL bounds. If the time is in the past, the entry is
+    /// written already expired.
+    pub async fn write_until<Q>(&self, key: &Q, value: V, expires_at: SystemTime)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let valid_until = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or(0)
+            .max(1);
+        let now = self.now();
+        let expires_in = Duration::from_millis(valid_until.saturating_sub(now) as u64);
+        let Ok(mut entry) = self.cache_entry(Some(expires_in), value) else {
+            return;
+        };
+        // Times in the past aren't raised to the min TTL.
+        if valid_until <= now {
+            entry.valid_until = valid_until;
+        }
+        self.write_backend(key, &entry.value, Some(expires_in))
+            .await;
+        self.insert_entry(key.to_owned(), entry);
+    }
+
+    /// Same as `write`, but the entry expires after `base_ttl` changed by a
+    /// random amount of up to `jitter_fraction` of it in either direction, so
+    /// entries written together don't expire at once. The fraction is clamped
+    /// to `0.0..=1.0`, and NaN disables the jitter.
+    pub async fn write_jittered<Q>(
+        &self,
+        key: &Q,
+        value: V,
+        base_ttl: Duration,
+        jitter_fraction: f64,
+    ) where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let fraction = if jitter_fraction.is_nan() {
+            0.0
+        } else {
+            jitter_fraction.clamp(0.0, 1.0)
+        };
+        let jitter = fraction * (self.next_random() * 2.0 - 1.0);
+        // TTLs too long to be scaled, like `Duration::MAX`, are kept as is.
+        let expires_in = Duration::try_from_secs_f64(base_ttl.as_secs_f64() * (1.0 + jitter))
+            .unwrap_or(base_ttl);
+        self.write(key, value, Some(expires_in)).await;
+    }
+
+    /// Seeds the random generator used by `write_jittered`, making the jitter
+    /// deterministic.
+    pub fn set_jitter_seed(&self, seed: u64) {
+        *lock(&self.jitter) = seed;
+    }
+
+    // SplitMix64, returning a number in `0.0..1.0`.
+    fn next_random(&self) -> f64 {
+        let mut state = lock(&self.jitter);
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Same as `write`, but also tags the entry, so it can be removed together
+    /// with other entries sharing a tag using `invalidate_tag`.
+    pub async fn write_tagged<Q>(
+        &self,
+        key: &Q,
+        value: V,
+        expires_in: Option<Duration>,
+        tags: &[&str],
+    ) where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let Ok(mut entry) = self.cache_entry(expires_in, value) else {
+            return;
+        };
+        self.write_backend(key, &entry.value, expires_in).await;
+        entry.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        let key = key.to_owned();
+        self.insert_entry(key.clone(), entry);
+
+        self.tag(&key, tags);
+    }
+
+    /// Starts a fluent write of the key, e.g.
+    /// `cache.entry("key").value(value).ttl(ttl).tags(&["tag"]).write().await`.
+    /// Without `ttl`, entries use the default TTL, same as `write`.
+    pub fn entry<Q>(&self, key: &Q) -> EntryKey<'_, V, K, S>
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        EntryKey {
+            cache: self,
+            key: key.to_owned(),
+        }
+    }
+
+    /// Removes all the entries tagged with `tag` and returns their count.
+    pub fn invalidate_tag(&self, tag: &str) -> usize {
+        let Some(keys) = lock(&self.tags).remove(tag) else {
+            return 0;
+        };
+        let mut removed = Vec::new();
+        for key in keys {
+            let mut cache = write_shard(self.shard(&key));
+            // The key might have been overwritten without the tag since.
+            if cache
+                .get(&key)
+                .is_some_and(|entry| entry.tags.iter().any(|t| t == tag))
+            {
+                if let Some((key, entry)) = cache.remove_entry(&key) {
+                    drop(cache);
+                    self.evicted(vec![(key.clone(), entry)]);
+                    removed.push(key);
+                }
+            }
+        }
+        let count = removed.len();
+        self.delete_backend(removed);
+        count
+    }
+
+    /// Writes the value only if the key is missing or expired. Returns whether
+    /// it was written.
+    pub async fn write_nx<Q>(&self, key: &Q, value: V, expires_in: Option<Duration>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let Ok(entry) = self.cache_entry(expires_in, value) else {
+            return false;
+        };
+        let value = entry.value.clone();
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        if cache.get_mut(key).is_some_and(|entry| entry.is_valid(now)) {
+            return false;
+        }
+        cache.insert(key.to_owned(), entry);
+        self.release(cache);
+        self.publish_written(key);
+        self.write_backend(key, &value, expires_in).await;
+        true
+    }
+
+    /// Returns the valid entry for the key, or writes `default` and returns it
+    /// if the key is missing or expired. Locks the store only once.
+    pub fn get_or_insert<Q>(&self, key: &Q, default: V, expires_in: Option<Duration>) -> V
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        if let Some(entry) = cache.get_mut(key).filter(|entry| entry.is_valid(now)) {
+            return V::clone(&entry.value);
+        }
+        let Ok(entry) = self.cache_entry(expires_in, default.clone()) else {
+            return default;
+        };
+        self.set_backend(key, &entry, now);
+        cache.insert(key.to_owned(), entry);
+        self.release(cache);
+        self.publish_written(key);
+        default
+    }
+
+    /// Modifies a valid entry in place, keeping its expiry. Returns `false`
+    /// without calling `f` if the key is missing or expired.
+    pub fn update<F: FnOnce(&mut V), Q>(&self, key: &Q, f: F) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = write_shard(self.shard(key));
+        match cache.get_mut(key) {
+            Some(entry) if entry.is_valid(now) => {
+                f(entry.value_mut());
+                self.set_backend(key, entry, now);
+                cache.resize(key);
+                drop(cache);
+                self.publish_written(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces a valid entry with `new` only if it's equal to `expected`.
+    /// Returns whether the value was swapped.
+    pub fn compare_and_swap<Q>(
+        &self,
+        key: &Q,
+        expected: &V,
+        new: V,
+        expires_in: Option<Duration>,
+    ) -> bool
+    where
+        V: PartialEq,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        match cache.get_mut(key) {
+            Some(entry) if entry.is_valid(now) && *entry.value == *expected => {
+                let Ok(new) = self.cache_entry(expires_in, new) else {
+                    return false;
+                };
+                self.set_backend(key, &new, now);
+                cache.insert(key.to_owned(), new);
+                self.release(cache);
+                self.publish_written(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns a valid entry together with its version, which changes on
+    /// every write and update of the key. Like `peek`, it doesn't count as a
+    /// use of the entry.
+    pub fn read_versioned<Q>(&self, key: &Q) -> Option<(V, u64)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let cache = read_shard(self.shard(key));
+        cache
+            .get(key)
+            .filter(|entry| entry.is_valid(now))
+            .map(|entry| (V::clone(&entry.value), entry.version))
+    }
+
+    /// Replaces a valid entry with `value` only if its version still equals
+    /// `expected_version`, as returned by `read_versioned`. Returns whether
+    /// the value was written.
+    pub fn write_if_version<Q>(
+        &self,
+        key: &Q,
+        value: V,
+        expected_version: u64,
+        expires_in: Option<Duration>,
+    ) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        match cache.get_mut(key) {
+            Some(entry) if entry.is_valid(now) && entry.version == expected_version => {
+                let Ok(value) = self.cache_entry(expires_in, value) else {
+                    return false;
+                };
+                self.set_backend(key, &value, now);
+                cache.insert(key.to_owned(), value);
+                self.release(cache);
+                self.publish_written(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes multiple entries at once, locking the store only once. Values
+    /// larger than the `set_max_entry_bytes` limit are skipped.
+    pub async fn mset(&self, entries: Vec<(K, V, Option<Duration>)>) {
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter_map(|(key, value, expires_in)| {
+                let entry = self.cache_entry(expires_in, value).ok()?;
+                Some((key, entry, expires_in))
+            })
+            .collect();
+        for (key, entry, expires_in) in &entries {
+            self.write_backend(key, &entry.value, *expires_in).await;
+        }
+        let keys: Vec<K> = entries.iter().map(|(key, _, _)| key.clone()).collect();
+        let mut cache = self.write_lock_all();
+        for (key, entry, _) in entries {
+            cache.insert(key, entry);
+        }
+        self.release(cache);
+        for key in keys {
+            self.publish(|| CacheEvent::Written { key });
+        }
+    }
+
+    /// Prefills the cache, e.g. at startup. `loader` is called once with all
+    /// the keys and returns their values in the same order. Keys it returns
+    /// `None` for are skipped, and the rest are written with their expiry
+    /// locking the store only once.
+    pub async fn warm<F, Fut>(&self, entries: Vec<(K, Option<Duration>)>, loader: F)
+    where
+        F: FnOnce(Vec<K>) -> Fut,
+        Fut: Future<Output = Vec<Option<V>>>,
+    {
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect();
+        let values = loader(keys).await;
+        let entries = entries
+            .into_iter()
+            .zip(values)
+            .filter_map(|((key, expires_in), value)| Some((key, value?, expires_in)))
+            .collect();
+        self.mset(entries).await;
+    }
+
+    /// Same as `mset`, but returns the values it overwrote, in the same order
+    /// as `entries`. Missing or expired keys, and skipped values, report
+    /// `None`.
+    pub async fn write_many(&self, entries: Vec<(K, V, Option<Duration>)>) -> Vec<Option<V>> {
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value, expires_in)| {
+                let entry = self.cache_entry(expires_in, value).ok();
+                (key, entry, expires_in)
+            })
+            .collect();
+        for (key, entry, expires_in) in &entries {
+            if let Some(entry) = entry {
+                self.write_backend(key, &entry.value, *expires_in).await;
+            }
+        }
+        let now = self.now();
+        let keys: Vec<K> = entries
+            .iter()
+            .filter(|(_, entry, _)| entry.is_some())
+            .map(|(key, _, _)| key.clone())
+            .collect();
+        let mut cache = self.write_lock_all();
+        let previous = entries
+            .into_iter()
+            .map(|(key, entry, _)| {
+                let entry = entry?;
+                let previous = cache
+                    .get_mut(&key)
+                    .filter(|entry| entry.is_valid(now))
+                    .map(|entry| V::clone(&entry.value));
+                cache.insert(key, entry);
+                previous
+            })
+            .collect();
+        self.release(cache);
+        for key in keys {
+            self.publish(|| CacheEvent::Written { key });
+        }
+        previous
+    }
+
+    /// Resets the expiry of a valid entry, keeping its value. Returns `false`
+    /// if the key is missing or expired.
+    pub fn touch<Q>(&self, key: &Q, expires_in: Option<Duration>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = write_shard(self.shard(key));
+        match cache.get(key) {
+            Some(entry) if entry.is_valid(now) => {
+                cache.set_valid_until(key, self.valid_until(expires_in));
+                if let Some(entry) = cache.get(key) {
+                    self.set_backend(key, entry, now);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Expires a valid entry immediately. Unlike `delete`, the entry stays in
+    /// the store until it's purged or removed by the reaper. Returns `false`
+    /// if the key is missing or already expired.
+    pub fn expire_now<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = write_shard(self.shard(key));
+        match cache.get(key) {
+            Some(entry) if entry.is_valid(now) => {
+                // The earliest expiry, as 0 means no expiry.
+                cache.set_valid_until(key, 1);
+                drop(cache);
+                self.delete_backend(std::iter::once_with(|| key.to_owned()));
+                self.publish(|| CacheEvent::Expired {
+                    key: key.to_owned(),
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves a valid entry to a new key, keeping its expiry and overwriting
+    /// any entry already stored there. Returns `false` if `from` is missing or
+    /// expired.
+    pub fn rename<Q>(&self, from: &Q, to: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        if from == to {
+            return self.contains_key(from);
+        }
+        let now = self.now();
+        let mut cache = self.write_lock_all();
+        if !cache.get_mut(from).is_some_and(|entry| entry.is_valid(now)) {
+            return false;
+        }
+        let Some((from, entry)) = cache.remove_entry(from) else {
+            return false;
+        };
+        let tags = entry.tags.clone();
+        let to = to.to_owned();
+        if self.backend.is_some() {
+            let ttl = (entry.valid_until != 0).then(|| remaining(entry.valid_until, now));
+            let written = (to.clone(), V::clone(&entry.value), ttl);
+            self.spawn_backend(vec![from.clone()], Some(written));
+        }
+        cache.insert(to.clone(), entry);
+        self.release(cache);
+        self.untag(&from, &tags);
+        self.tag(&to, &tags);
+        self.publish(|| CacheEvent::Deleted { key: from });
+        self.publish(|| CacheEvent::Written { key: to });
+        true
+    }
+
+    /// Same as `delete`, but waits until the key is deleted from the backend
+    /// too.
+    pub async fn delete_through<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let value = self.delete_local(key);
+        if let Some(backend) = &self.backend {
+            backend.delete(&key.to_owned()).await;
+        }
+        value
+    }
+
+    /// Removes a valid entry and returns its value under a single lock, so
+    /// only one of concurrent calls for the same key gets the value. Same as
+    /// `delete`, but named for single-use values, e.g. one-time tokens.
+    pub fn take<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        self.delete(key)
+    }
+
+    /// Removes the key and returns its value if it was valid. With a backend,
+    /// the key is deleted from it in a spawned task, see `with_backend`.
+    pub fn delete<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let value = self.delete_local(key);
+        self.delete_backend(std::iter::once_with(|| key.to_owned()));
+        value
+    }
+
+    fn delete_local<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        let now = self.now();
+        let removed = write_shard(self.shard(key)).remove_entry(key);
+        debug_event!(
+            op = "delete",
+            key = ?key,
+            outcome = if removed.is_some() { "deleted" } else { "missing" }
+        );
+        let (key, entry) = removed?;
+        self.untag(&key, &entry.tags);
+        if let Some(callback) = evict_callback(&self.on_evict) {
+            callback(&key, &entry.value);
+        }
+        if entry.is_valid(now) {
+            self.publish(|| CacheEvent::Deleted { key });
+            Some(Arc::unwrap_or_clone(entry.value))
+        } else {
+            self.expirations.fetch_add(1, Ordering::Relaxed);
+            self.publish(|| CacheEvent::Expired { key });
+            None
+        }
+    }
+
+    /// Deletes multiple keys at once, locking the store only once. Returns the
+    /// number of valid entries deleted, so missing or expired keys don't
+    /// count.
+    pub fn delete_many<Q>(&self, keys: &[&Q]) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let removed: Removed<K, V> = {
+            let mut shards = write_all(&self.store);
+            keys.iter()
+                .filter_map(|key| {
+                    let removed = shards[self.store.index(*key)].remove_entry(*key);
+                    debug_event!(
+                        op = "delete",
+                        key = ?key,
+                        outcome = if removed.is_some() { "deleted" } else { "missing" }
+                    );
+                    removed
+                })
+                .collect()
+        };
+        let deleted = removed
+            .iter()
+            .filter(|(_, entry)| entry.is_valid(now))
+            .count();
+        self.expirations
+            .fetch_add((removed.len() - deleted) as u64, Ordering::Relaxed);
+        self.evicted(removed);
+        self.delete_backend(keys.iter().map(|key| (*key).to_owned()));
+        deleted
+    }
+
+    /// Returns hit and miss counts of `read` calls, eviction and expiration
+    /// counts, and the current number of entries. Reading an expired entry
+    /// counts as a miss.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            entries: self.len(),
+            peak_bytes: self.store.peak_bytes().load(Ordering::Relaxed),
+            rejections: self.entry_limit.rejections.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts valid entries and expired ones still occupying memory in a
+    /// single scan, locking the store only once. A growing `expired_present`
+    /// count means the reaper doesn't keep up.
+    pub fn raw_stats(&self) -> RawStats {
+        let now = self.now();
+        let shards = read_all(&self.store);
+        let (live, total) = shards
+            .iter()
+            .flat_map(|shard| shard.values())
+            .fold((0, 0), |(live, total), entry| {
+                (live + entry.is_valid(now) as usize, total + 1)
+            });
+        RawStats {
+            live,
+            expired_present: total - live,
+            total,
+        }
+    }
+
+    /// Resets all the counters. The entries count is not a counter, so it
+    /// stays unchanged.
+    pub fn reset_stats(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.evictions.store(0, Ordering::Relaxed);
+        self.expirations.store(0, Ordering::Relaxed);
+        self.store.peak_bytes().store(0, Ordering::Relaxed);
+        self.entry_limit.rejections.store(0, Ordering::Relaxed);
+        for counters in lock(&self.namespace_stats).values() {
+            counters.reset();
+        }
+    }
+
+    /// Limits the number of stored entries. When the limit is reached, `write`
+    /// evicts an entry picked by the configured `EvictionPolicy`, preferring
+    /// expired ones. `None` disables the limit. While a limit is set, the
+    /// entries are kept in the eviction order, so evictions don't scan the
+    /// store.
+    pub fn set_max_entries(&self, max_entries: Option<usize>) {
+        lock(&self.config).max_entries = max_entries;
+        self.apply_limits();
+    }
+
+    /// Replaces the cache configuration. If the config has a reaper interval,
+    /// starts the reaper and returns its handle, so it must be called from
+    /// within a tokio runtime.
+    pub fn configure(&self, config: ZCacheConfig) -> Option<ReaperHandle>
+    where
+        V: Sync,
+    {
+        let reaper_interval = config.reaper_interval;
+        *lock(&self.config) = config;
+        self.apply_limits();
+        reaper_interval.map(|interval| self.start_reaper(interval))
+    }
+
+    /// Removes all the expired entries and returns their keys. Unlike the
+    /// reaper, it runs synchronously.
+    pub fn purge_expired(&self) -> Vec<K> {
+        let now = self.now();
+        let removed: Removed<K, V> = self
+            .store
+            .iter()
+            .flat_map(|shard| remove_expired(&mut write_shard(shard), now))
+            .collect();
+        self.expirations
+            .fetch_add(removed.len() as u64, Ordering::Relaxed);
+        prune_loaders(&self.negative, &self.breakers, now);
+        let keys = removed.iter().map(|(key, _)| key.clone()).collect();
+        self.evicted(removed);
+        keys
+    }
+
+    /// Runs a single reaper sweep on the calling thread and returns the
+    /// number of expired entries removed. Useful for driving the reaper
+    /// deterministically together with a `MockClock`.
+    pub fn reap_once(&self) -> usize {
+        let now = self.now();
+        prune_loaders(&self.negative, &self.breakers, now);
+        reap(
+            &self.store,
+            now,
+            &self.expirations,
+            &self.tags,
+            &self.on_evict,
+            &self.events,
+        )
+    }
+
+    /// Removes at most `max` expired entries and returns their count, locking
+    /// a single shard at a time, so large caches can be cleaned up in small
+    /// steps. A single call doesn't guarantee all the expired entries are
+    /// removed, it must be repeated until it returns less than `max`. Cached
+    /// misses and breakers are left to the reaper and `purge_expired`, which
+    /// scan them all.
+    pub fn reap_batch(&self, max: usize) -> usize {
+        let now = self.now();
+        let mut removed: Removed<K, V> = Vec::new();
+        for shard in self.store.iter() {
+            if removed.len() >= max {
+                break;
+            }
+            let mut cache = write_shard(shard);
+            let expired: Vec<K> = cache
+                .iter()
+                .filter(|(_, entry)| !entry.is_valid(now))
+                .map(|(key, _)| key.clone())
+                .take(max - removed.len())
+                .collect();
+            removed.extend(expired.iter().filter_map(|key| cache.remove_entry(key)));
+        }
+        let count = removed.len();
+        self.expirations.fetch_add(count as u64, Ordering::Relaxed);
+        self.evicted(removed);
+        count
+    }
+
+    /// Spawns a tokio task removing expired entries from the store every
+    /// `interval`. If a reaper is already running, returns its handle instead
+    /// of starting another one. Must be called from within a tokio runtime.
+    pub fn start_reaper(&self, interval: Duration) -> ReaperHandle
+    where
+        V: Sync,
+    {
+        let mut reaper = lock(&self.reaper);
+        if let Some(abort_handle) = reaper.as_ref() {
+            if !abort_handle.is_finished() {
+                return ReaperHandle {
+                    abort_handle: abort_handle.clone(),
+                    slot: self.reaper.clone(),
+                };
+            }
+        }
+
+        let store = self.store.clone();
+        let clock = self.clock.clone();
+        let on_evict = self.on_evict.clone();
+        let expirations = self.expirations.clone();
+        let tags = self.tags.clone();
+        let events = self.events.clone();
+        let negative = self.negative.clone();
+        let breakers = self.breakers.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let now = read_clock(&clock);
+                prune_loaders(&negative, &breakers, now);
+                reap(&store, now, &expirations, &tags, &on_evict, &events);
+            }
+        });
+        let abort_handle = Arc::new(task.abort_handle());
+        *reaper = Some(abort_handle.clone());
+        ReaperHandle {
+            abort_handle,
+            slot: self.reaper.clone(),
+        }
+    }
+
+    /// Saves all valid entries as JSON, together with their absolute expiry
+    /// timestamps.
+    #[cfg(feature = "serde")]
+    pub fn save_to_path(&self, path: &Path) -> std::io::Result<()>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(&mut file, &self.snapshot())?;
+        // Dropping the writer flushes it too, but ignores write errors.
+        std::io::Write::flush(&mut file)
+    }
+
+    /// Loads a snapshot saved with `save_to_path`, merging it into the store.
+    /// Loaded keys overwrite existing ones, and entries that expired in the
+    /// meantime are skipped.
+    #[cfg(feature = "serde")]
+    pub fn load_from_path(&self, path: &Path) -> std::io::Result<()>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+    {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        self.restore(serde_json::from_reader(file)?);
+        Ok(())
+    }
+
+    /// Sets the path `flush` saves the snapshot to, so a restarted app can
+    /// load it with `load_from_path`. Nothing is saved automatically, the app
+    /// must call `flush` from its own shutdown hook.
+    #[cfg(feature = "serde")]
+    pub fn persist_on_shutdown(&self, path: PathBuf) {
+        *lock(&self.persist_path) = Some(path);
+    }
+
+    /// Saves a snapshot to the path set with `persist_on_shutdown`, same as
+    /// `save_to_path`. Does nothing if no path was set.
+    #[cfg(feature = "serde")]
+    pub fn flush(&self) -> std::io::Result<()>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        let path = lock(&self.persist_path).clone();
+        match path {
+            Some(path) => self.save_to_path(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as `save_to_path`, but uses the compact bincode format. Unlike
+    /// JSON, bincode is not self-describing, so snapshots can only be loaded
+    /// by a version of the crate with the same `ZEntry` layout.
+    #[cfg(feature = "bincode")]
+    pub fn save_to_path_bin(&self, path: &Path) -> std::io::Result<()>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        bincode::serialize_into(&mut file, &self.snapshot()).map_err(std::io::Error::other)?;
+        std::io::Write::flush(&mut file)
+    }
+
+    /// Loads a snapshot saved with `save_to_path_bin`, same as `load_from_path`.
+    #[cfg(feature = "bincode")]
+    pub fn load_from_path_bin(&self, path: &Path) -> std::io::Result<()>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+    {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        self.restore(bincode::deserialize_from(file).map_err(std::io::Error::other)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    fn snapshot(&self) -> Vec<SnapshotEntry<K, V>> {
+        let now = self.now();
+        self.store
+            .iter()
+            .flat_map(|shard| {
+                read_shard(shard)
+                    .iter()
+                    .filter(|(_, entry)| entry.is_valid(now))
+                    .map(|(key, entry)| SnapshotEntry {
+                        key: key.clone(),
+                        valid_until: entry.valid_until,
+                        value: V::clone(&entry.value),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    fn restore(&self, snapshot: Vec<SnapshotEntry<K, V>>) {
+        let now = self.now();
+        for entry in snapshot {
+            if is_valid(entry.valid_until, now) && self.check_size(&entry.value).is_ok() {
+                let value = CacheEntry::new(entry.valid_until, entry.value, now);
+                self.insert_entry(entry.key, value);
+            }
+        }
+    }
+
+    /// Removes all the entries and returns the number of valid ones. Store
+    /// shards are cleared one by one, so entries written concurrently with
+    /// `clear` might survive it.
+    pub fn clear(&self) -> usize {
+        let now = self.now();
+        let callback = evict_callback(&self.on_evict);
+        let mut cleared = 0;
+        for shard in self.store.iter() {
+            let removed = write_shard(shard).drain();
+            cleared += removed
+                .iter()
+                .filter(|(_, entry)| entry.is_valid(now))
+                .count();
+            if let Some(callback) = &callback {
+                for (key, entry) in &removed {
+                    callback(key, &entry.value);
+                }
+            }
+            self.delete_backend(removed.into_iter().map(|(key, _)| key));
+        }
+        lock(&self.tags).clear();
+        lock(&self.negative).clear();
+        lock(&self.breakers).clear();
+        self.publish(|| CacheEvent::Cleared);
+        cleared
+    }
+
+    /// Removes all the valid entries for which `predicate` returns `true`
+    /// and returns their count. Store shards are visited one by one, like in
+    /// `clear`.
+    pub fn clear_if<P: Fn(&K, &V) -> bool>(&self, predicate: P) -> usize {
+        let now = self.now();
+        self.remove_where(|key, entry| entry.is_valid(now) && predicate(key, &entry.value))
+    }
+
+    fn remove_where<P: Fn(&K, &CacheEntry<V>) -> bool>(&self, predicate: P) -> usize {
+        let removed: Removed<K, V> = self
+            .store
+            .iter()
+            .flat_map(|shard| {
+                let mut cache = write_shard(shard);
+                let keys: Vec<K> = cache
+                    .iter()
+                    .filter(|(key, entry)| predicate(key, entry))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                keys.into_iter()
+                    .filter_map(|key| cache.remove_entry(&key))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let count = removed.len();
+        self.delete_backend(removed.iter().map(|(key, _)| key.clone()));
+        self.evicted(removed);
+        count
+    }
+
+    /// Registers a callback called with the key and value of every entry
+    /// leaving the store, whether deleted, expired or evicted. Overwriting
+    /// an entry doesn't call it. It runs after the store locks are released,
+    /// so it can access the cache.
+    pub fn on_evict<F: Fn(&K, &V) + Send + Sync + 'static>(&self, f: F) {
+        *self
+            .on_evict
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(f));
+    }
+
+    /// Same as `on_evict`, but registers an async callback, which is spawned
+    /// as a separate task with owned copies of the key and value, so its
+    /// work and panics don't affect cache operations. Replaces the `on_evict`
+    /// callback. Must be called from within a tokio runtime, which runs the
+    /// callbacks.
+    pub fn on_evict_async<F, Fut>(&self, f: F)
+    where
+        F: Fn(K, V) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let runtime = tokio::runtime::Handle::current();
+        self.on_evict(move |key, value| {
+            runtime.spawn(f(key.clone(), value.clone()));
+        });
+    }
+
+    // Releases the store locks, then untags overwritten entries and evicts
+    // entries over the limits. Expired entries removed by the limits are
+    // counted as expirations.
+    fn release(&self, cache: StoreWriteGuard<'_, K, V, S>) {
+        let written = cache.into_written();
+        for (key, tags) in &written.untagged {
+            self.untag(key, tags);
+        }
+        let now = self.now();
+        let evicted = self.store.evict(written.inserted.as_ref(), now);
+        let expired = evicted
+            .iter()
+            .filter(|(_, entry)| !entry.is_valid(now))
+            .count();
+        #[cfg(feature = "tracing")]
+        for (key, _) in &evicted {
+            debug_event!(op = "evict", key = ?key);
+        }
+        self.evictions
+            .fetch_add((evicted.len() - expired) as u64, Ordering::Relaxed);
+        self.expirations
+            .fetch_add(expired as u64, Ordering::Relaxed);
+        self.evicted(evicted);
+    }
+
+    fn evicted(&self, removed: Removed<K, V>) {
+        let now = self.now();
+        let callback = evict_callback(&self.on_evict);
+        for (key, entry) in removed {
+            self.untag(&key, &entry.tags);
+            if let Some(callback) = &callback {
+                callback(&key, &entry.value);
+            }
+            match entry.is_valid(now) {
+                true => self.publish(|| CacheEvent::Deleted { key }),
+                false => self.publish(|| CacheEvent::Expired { key }),
+            }
+        }
+    }
+
+    /// Returns a receiver of events published after every change of the
+    /// cache. Receivers which fall more than 1024 events behind miss the
+    /// oldest ones, reported as `RecvError::Lagged`, so slow receivers never
+    /// block writers. Background refreshes of `fetch_swr` and
+    /// `fetch_refresh_ahead` entries are not published.
+    pub fn subscribe(&self) -> broadcast::Receiver<CacheEvent<K>> {
+        self.events.subscribe()
+    }
+
+    // Events are built only if there are any receivers.
+    fn publish<F: FnOnce() -> CacheEvent<K>>(&self, event: F) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
+        }
+    }
+
+    fn publish_written<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.publish(|| CacheEvent::Written {
+            key: key.to_owned(),
+        });
+    }
+
+    fn tag<T: AsRef<str>>(&self, key: &K, tags: &[T]) {
+        if tags.is_empty() {
+            return;
+        }
+        let mut index = lock(&self.tags);
+        for tag in tags {
+            index
+                .entry(tag.as_ref().to_string())
+                .or_default()
+                .insert(key.clone());
+        }
+    }
+
+    fn untag(&self, key: &K, tags: &[String]) {
+        untag(&self.tags, key, tags);
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &Shard<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        self.store.shard(key)
+    }
+
+    fn insert_entry(&self, key: K, entry: CacheEntry<V>) {
+        debug_event!(op = "write", key = ?key);
+        let mut cache = self.write_lock(&key);
+        cache.insert(key.clone(), entry);
+        self.release(cache);
+        self.publish(|| CacheEvent::Written { key });
+    }
+
+    // Inserts through the guard are checked against the limits once it's
+    // passed to `release`.
+    fn write_lock<Q>(&self, key: &Q) -> StoreWriteGuard<'_, K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        StoreWriteGuard::Shard {
+            shard: write_shard(self.shard(key)),
+            written: Written::default(),
+        }
+    }
+
+    // Entries written without expiry use the configured default TTL, and
+    // all the TTLs are clamped into the configured bounds.
+    fn valid_until(&self, expires_in: Option<Duration>) -> u128 {
+        let expires_in = lock(&self.config).effective_ttl(expires_in);
+        valid_until(expires_in, self.now())
+    }
+
+    // Entries are built only from values within the `set_max_entry_bytes`
+    // limit.
+    fn cache_entry(
+        &self,
+        expires_in: Option<Duration>,
+        value: V,
+    ) -> Result<CacheEntry<V>, ZCacheError> {
+        self.check_size(&value)?;
+        Ok(CacheEntry::new(
+            self.valid_until(expires_in),
+            value,
+            self.now(),
+        ))
+    }
+
+    fn now(&self) -> u128 {
+        read_clock(&self.clock)
+    }
+
+    fn write_lock_all(&self) -> StoreWriteGuard<'_, K, V, S> {
+        StoreWriteGuard::All {
+            store: &self.store,
+            shards: write_all(&self.store),
+            written: Written::default(),
+        }
+    }
+
+    // The limits are read with all the shards locked, so concurrent changes
+    // are applied in order.
+    fn apply_limits(&self) {
+        let mut shards = write_all(&self.store);
+        let limits = {
+            let config = lock(&self.config);
+            Limits {
+                max_entries: config.max_entries,
+                max_bytes: *lock(&self.byte_limit),
+                policy: config.eviction_policy,
+            }
+        };
+        self.store.set_limits(&mut shards, limits);
+    }
+}
+
+impl<V: Clone + Send + 'static, S: BuildHasher + Send + Sync + 'static> Cache<V, String, S> {
+    /// Returns a handle prefixing all the keys with `ns:`, so that separate
+    /// namespaces don't collide while sharing the same store.
+    pub fn namespace(&self, ns: &str) -> Namespace<'_, V, S> {
+        let stats = lock(&self.namespace_stats)
+            .entry(ns.to_string())
+            .or_default()
+            .clone();
+        Namespace {
+            cache: self,
+            prefix: format!("{}:", ns),
+            stats,
+        }
+    }
+
+    /// Returns stats of reads through `namespace` handles of `ns`, and the
+    /// number of its valid entries. Other reads are counted for the default
+    /// `""` namespace, together with entries outside of any namespace used so
+    /// far. Evictions and expirations are only counted for the whole cache.
+    pub fn stats_for_namespace(&self, ns: &str) -> CacheStats {
+        let namespaces = lock(&self.namespace_stats);
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (hits, misses, entries) = if ns.is_empty() {
+            // Namespaced reads are also counted for the whole cache.
+            let (hits, misses) = namespaces.values().fold(
+                (load(&self.hits), load(&self.misses)),
+                |(hits, misses), counters| {
+                    (
+                        hits.saturating_sub(load(&counters.hits)),
+                        misses.saturating_sub(load(&counters.misses)),
+                    )
+                },
+            );
+            let prefixes: Vec<String> = namespaces.keys().map(|ns| format!("{}:", ns)).collect();
+            drop(namespaces);
+            let entries = self.count_valid(|key| !prefixes.iter().any(|p| key.starts_with(p)));
+            (hits, misses, entries)
+        } else {
+            let (hits, misses) = namespaces
+                .get(ns)
+                .map(|counters| (load(&counters.hits), load(&counters.misses)))
+                .unwrap_or_default();
+            drop(namespaces);
+            let prefix = format!("{}:", ns);
+            (
+                hits,
+                misses,
+                self.count_valid(|key| key.starts_with(&prefix)),
+            )
+        };
+        CacheStats {
+            hits,
+            misses,
+            evictions: 0,
+            expirations: 0,
+            entries,
+            peak_bytes: 0,
+            rejections: 0,
+        }
+    }
+
+    fn count_valid<P: Fn(&String) -> bool>(&self, predicate: P) -> usize {
+        let now = self.now();
+        read_all(&self.store)
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(key, entry)| predicate(key) && entry.is_valid(now))
+            .count()
+    }
+
+    /// Same as `fetch`, but memoizes `f` by its arguments, hashing `args`
+    /// together with their type name into a `keyed:<hash>` key. Arguments
+    /// equal by `Hash` always share an entry. The hash has 64 bits, so
+    /// different arguments are very unlikely to collide, but if they do, one
+    /// gets the value cached for the other. Keys are only stable within one
+    /// build, so they shouldn't be persisted or shared with other programs.
+    pub async fn fetch_keyed<A, F, Fut>(
+        &self,
+        args: &A,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        A: Hash + ?Sized,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+    {
+        self.fetch(keyed(args).as_str(), expires_in, f).await
+    }
+
+    /// Removes all the keys of the `ns` namespace and returns their count.
+    pub fn clear_namespace(&self, ns: &str) -> usize {
+        self.clear_prefix(&format!("{}:", ns))
+    }
+
+    /// Removes all the keys starting with `prefix` and returns their count.
+    pub fn clear_prefix(&self, prefix: &str) -> usize {
+        self.remove_where(|key, _| key.starts_with(prefix))
+    }
+
+    /// Returns all valid entries with keys starting with `prefix`, locking
+    /// the store only once. It doesn't count as a read. Ordering is
+    /// unspecified.
+    pub fn scan_prefix(&self, prefix: &str) -> Vec<(String, V)> {
+        let now = self.now();
+        read_all(&self.store)
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(key, entry)| key.starts_with(prefix) && entry.is_valid(now))
+            .map(|(key, entry)| (key.clone(), V::clone(&entry.value)))
+            .collect()
+    }
+}
+
+impl<V: HeapSize + Clone + Send + 'static, S: BuildHasher + Send + Sync + 'static>
+    Cache<V, String, S>
+{
+    /// Limits the approximate memory used by the stored keys and values.
+    /// When a `write` exceeds it, other entries are evicted in the order of the
+    /// configured `EvictionPolicy`, preferring expired ones. `None` disables
+    /// the limit. Values modified in place aren't checked against it.
+    pub fn set_max_bytes(&self, max_bytes: Option<usize>) {
+        self.store.measure(|key, value| entry_size(key, value));
+        *lock(&self.byte_limit) = max_bytes;
+        self.apply_limits();
+    }
+
+    /// Limits the size of single values, as measured by `heap_size`. Methods
+    /// returning a `Result`, like `try_write` and `fetch`, fail with
+    /// `EntryTooLarge` for larger values, and others skip them. Either way,
+    /// they're counted in `stats().rejections`. Values modified in place with
+    /// `update` aren't checked. `None` or `Some(0)` disables the limit.
+    pub fn set_max_entry_bytes(&self, max_entry_bytes: Option<usize>) {
+        *lock(&self.entry_limit.limit) = max_entry_bytes
+            .filter(|limit| *limit > 0)
+            .map(|limit| (limit, V::heap_size as _));
+    }
+
+    /// Approximate memory used by the keys and values of valid entries,
+    /// measured the same way as by `set_max_bytes` and `peak_bytes`. Unlike
+    /// them, it skips expired entries still kept in the store, so it scans
+    /// the store like `len`.
+    pub fn estimated_bytes(&self) -> usize {
+        self.store.measure(|key, value| entry_size(key, value));
+        let now = self.now();
+        self.store
+            .iter()
+            .map(|shard| {
+                read_shard(shard)
+                    .values()
+                    .filter(|entry| entry.is_valid(now))
+                    .map(|entry| entry.size)
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+impl<K, S> Cache<ZEntry, K, S>
+where
+    K: Eq + Hash + Clone + KeyDebug + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    /// Reads an entry converted with `TryFrom`, e.g. `read_typed::<i64>`.
+    /// Returns `None` if the key is missing or expired, or if the entry is of
+    /// another variant. Reading a mismatched entry still counts as a hit.
+    pub fn read_typed<T, Q>(&self, key: &Q) -> Option<T>
+    where
+        T: TryFrom<ZEntry>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ?Sized,
+    {
+        self.read(key).and_then(|entry| T::try_from(entry).ok())
+    }
+
+    /// Counts valid entries by their variant name, e.g. `"Int"`.
+    pub fn count_by_type(&self) -> HashMap<&'static str, usize> {
+        let now = self.now();
+        let mut counts = HashMap::new();
+        for shard in self.store.iter() {
+            for entry in read_shard(shard)
+                .values()
+                .filter(|entry| entry.is_valid(now))
+            {
+                *counts.entry(entry.value.type_name()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Returns all valid entries of the given variant, locking the store only
+    /// once. It doesn't count as a read. Ordering is unspecified.
+    pub fn read_all_by_type(&self, kind: ZEntryKind) -> Vec<(K, ZEntry)> {
+        let now = self.now();
+        read_all(&self.store)
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(_, entry)| entry.value.kind() == kind && entry.is_valid(now))
+            .map(|(key, entry)| (key.clone(), ZEntry::clone(&entry.value)))
+            .collect()
+    }
+
+    /// Atomically adds `by` to an `Int` entry and returns the new value,
+    /// keeping the entry expiry. Missing keys are created with the default
+    /// TTL, same as `write` without expiry.
+    pub fn increment<Q>(&self, key: &Q, by: i64) -> Result<i64, ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        if let Some(entry) = cache.get_mut(key).filter(|entry| entry.is_valid(now)) {
+            // The variant is checked first, so failed updates leave the entry
+            // and its version untouched.
+            let result = match *entry.value {
+                ZEntry::Int(value) => {
+                    let value = value
+                        .checked_add(by)
+                        .ok_or_else(|| ZCacheError::Overflow(key.to_string()))?;
+                    entry.set_value(ZEntry::Int(value));
+                    Ok(value)
+                }
+                ref other => Err(ZCacheError::TypeMismatch {
+                    expected: "Int",
+                    found: other.type_name(),
+                }),
+            };
+            if result.is_ok() {
+                self.set_backend(key, entry, now);
+            }
+            drop(cache);
+            if result.is_ok() {
+                self.publish_written(key);
+            }
+            return result;
+        }
+        let entry = self.cache_entry(None, ZEntry::Int(by))?;
+        self.set_backend(key, &entry, now);
+        cache.insert(key.to_owned(), entry);
+        self.release(cache);
+        self.publish_written(key);
+        Ok(by)
+    }
+
+    /// Atomically adds `by` to a `Float` entry and returns the new value,
+    /// keeping the entry expiry. Missing keys are created with the default
+    /// TTL, same as `write` without expiry.
+    /// It follows IEEE 754 arithmetic, so adding `NaN` or overflowing results
+    /// in `NaN` or an infinity being stored, without failing.
+    pub fn add_float<Q>(&self, key: &Q, by: f64) -> Result<f64, ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        if let Some(entry) = cache.get_mut(key).filter(|entry| entry.is_valid(now)) {
+            let result = match *entry.value {
+                ZEntry::Float(value) => {
+                    entry.set_value(ZEntry::Float(value + by));
+                    Ok(value + by)
+                }
+                ref other => Err(ZCacheError::TypeMismatch {
+                    expected: "Float",
+                    found: other.type_name(),
+                }),
+            };
+            if result.is_ok() {
+                self.set_backend(key, entry, now);
+            }
+            drop(cache);
+            if result.is_ok() {
+                self.publish_written(key);
+            }
+            return result;
+        }
+        let entry = self.cache_entry(None, ZEntry::Float(by))?;
+        self.set_backend(key, &entry, now);
+        cache.insert(key.to_owned(), entry);
+        self.release(cache);
+        self.publish_written(key);
+        Ok(by)
+    }
+
+    /// Atomically inverts a `Bool` entry and returns the new value, keeping
+    /// the entry expiry. Missing keys are created as `true` with the default
+    /// TTL, same as `write` without expiry.
+    pub fn toggle<Q>(&self, key: &Q) -> Result<bool, ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        if let Some(entry) = cache.get_mut(key).filter(|entry| entry.is_valid(now)) {
+            let result = match *entry.value {
+                ZEntry::Bool(value) => {
+                    entry.set_value(ZEntry::Bool(!value));
+                    Ok(!value)
+                }
+                ref other => Err(ZCacheError::TypeMismatch {
+                    expected: "Bool",
+                    found: other.type_name(),
+                }),
+            };
+            if result.is_ok() {
+                self.set_backend(key, entry, now);
+            }
+            drop(cache);
+            if result.is_ok() {
+                self.publish_written(key);
+            }
+            return result;
+        }
+        let entry = self.cache_entry(None, ZEntry::Bool(true))?;
+        self.set_backend(key, &entry, now);
+        cache.insert(key.to_owned(), entry);
+        self.release(cache);
+        self.publish_written(key);
+        Ok(true)
+    }
+
+    /// Atomically appends `suffix` to a `Text` entry and returns the new
+    /// value, keeping the entry expiry. Missing keys are created from
+    /// `suffix` with the default TTL, same as `write` without expiry. Fails
+    /// with `EntryTooLarge` if the new value is larger than the
+    /// `set_max_entry_bytes` limit.
+    pub fn append<Q>(&self, key: &Q, suffix: &str) -> Result<String, ZCacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + KeyDebug + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.now();
+        let mut cache = self.write_lock(key);
+        if let Some(entry) = cache.get_mut(key).filter(|entry| entry.is_valid(now)) {
+            let result = match &*entry.value {
+                ZEntry::Text(text) => {
+                    let text = [text.as_str(), suffix].concat();
+                    let value = ZEntry::Text(text.clone());
+                    self.check_size(&value)?;
+                    entry.set_value(value);
+                    Ok(text)
+                }
+                other => Err(ZCacheError::TypeMismatch {
+                    expected: "Text",
+                    found: other.type_name(),
+                }),
+            };
+            if result.is_ok() {
+                self.set_backend(key, entry, now);
+            }
+            cache.resize(key);
+            drop(cache);
+            if result.is_ok() {
+                self.publish_written(key);
+            }
+            return result;
+        }
+        let value = ZEntry::Text(suffix.to_string());
+        let entry = self.cache_entry(None, value)?;
+        self.set_backend(key, &entry, now);
+        cache.insert(key.to_owned(), entry);
+        self.release(cache);
+        self.publish_written(key);
+        Ok(suffix.to_string())
+    }
+}
+
+/// Handle to a namespace of a cache, created with `namespace`.
+pub struct Namespace<'a, V, S = RandomState> {
+    cache: &'a Cache<V, String, S>,
+    prefix: String,
+    stats: Arc<HitCounters>,
+}
+
+impl<V: Clone + Send + 'static, S: BuildHasher + Send + Sync + 'static> Namespace<'_, V, S> {
+    pub async fn fetch<F, Fut>(
+        &self,
+        key: &str,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+    {
+        let key = self.key(key);
+        if let Some(value) = self.read_key(&key) {
+            return Ok(value);
+        }
+        self.cache.load(&key, expires_in, f).await
+    }
+
+    pub fn read(&self, key: &str) -> Option<V> {
+        self.read_key(&self.key(key))
+    }
+
+    fn read_key(&self, key: &String) -> Option<V> {
+        let value = self.cache.read(key);
+        self.stats.record(value.is_some());
+        value
+    }
+
+    pub async fn write(&self, key: &str, value: V, expires_in: Option<Duration>) {
+        self.cache.write(&self.key(key), value, expires_in).await
+    }
+
+    pub fn delete(&self, key: &str) -> Option<V> {
+        self.cache.delete(&self.key(key))
+    }
+
+    pub fn clear(&self) -> usize {
+        self.cache
+            .remove_where(|key, _| key.starts_with(&self.prefix))
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+/// Key of a write started with `entry`, waiting for its value.
+pub struct EntryKey<'a, V, K = String, S = RandomState> {
+    cache: &'a Cache<V, K, S>,
+    key: K,
+}
+
+impl<'a, V, K, S> EntryKey<'a, V, K, S> {
+    pub fn value(self, value: V) -> EntryBuilder<'a, V, K, S> {
+        EntryBuilder {
+            cache: self.cache,
+            key: self.key,
+            value,
+            expires_in: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// Fluent write of a single entry, created with `entry`.
+pub struct EntryBuilder<'a, V, K = String, S = RandomState> {
+    cache: &'a Cache<V, K, S>,
+    key: K,
+    value: V,
+    expires_in: Option<Duration>,
+    tags: Vec<String>,
+}
+
+impl<V, K, S> EntryBuilder<'_, V, K, S>
+where
+    V: Clone + Send + 'static,
+    K: Eq + Hash + Clone + KeyDebug + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    pub fn ttl(mut self, expires_in: Duration) -> Self {
+        self.expires_in = Some(expires_in);
+        self
+    }
+
+    pub fn tags(mut self, tags: &[&str]) -> Self {
+        self.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        self
+    }
+
+    /// Same as `write`, or `write_tagged` if tags were set.
+    pub async fn write(self) {
+        let tags: Vec<&str> = self.tags.iter().map(String::as_str).collect();
+        self.cache
+            .write_tagged(&self.key, self.value, self.expires_in, &tags)
+            .await
+    }
+}
+
+/// Cache with an async API, whose store is guarded by a
+/// `tokio::sync::Mutex`. No `std` lock is held across an `.await`, and tasks
+/// waiting for the store yield to the runtime instead of blocking the thread.
+/// It supports only the basic operations, `Cache` has the full feature set.
+pub struct AsyncCache<V, K = String> {
+    store: tokio::sync::Mutex<HashMap<K, CacheEntry<V>>>,
+    in_flight: InFlightLocks<K>,
+    clock: SharedClock,
+}
+
+pub type AsyncZCacheInstance = AsyncCache<ZEntry>;
+
+impl<V, K> Default for AsyncCache<V, K> {
+    fn default() -> Self {
+        Self {
+            store: Default::default(),
+            in_flight: Default::default(),
+            clock: Arc::new(RwLock::new(Arc::new(SystemClock))),
+        }
+    }
+}
+
+impl<V, K> AsyncCache<V, K>
+where
+    V: Clone + Send + 'static,
+    K: Eq + Hash + Clone + KeyDebug + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let cache = Self::default();
+        *cache.clock.write().unwrap_or_else(PoisonError::into_inner) = clock;
+        cache
+    }
+
+    /// Same as `Cache::fetch`. The store is not locked while `f` runs.
+    pub async fn fetch<F, Fut, Q>(
+        &self,
+        key: &Q,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<V, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<V>>,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + fmt::Display + ?Sized,
+    {
+        if let Some(value) = self.read(key).await {
+            return Ok(value);
+        }
+
+        let _in_flight = InFlightGuard::acquire(&self.in_flight, key.to_owned()).await;
+        if let Some(value) = self.read(key).await {
+            return Ok(value);
+        }
+        match f().await {
+            Some(value) => {
+                self.write(key, value.clone(), expires_in).await;
+                Ok(value)
+            }
+            None => Err(ZCacheError::FetchError(key.to_string())),
+        }
+    }
+
+    pub async fn read<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = read_clock(&self.clock);
+        let store = self.store.lock().await;
+        store.get(key)?.access(now, 0).map(|value| V::clone(&value))
+    }
+
+    pub async fn write<Q>(&self, key: &Q, value: V, expires_in: Option<Duration>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = read_clock(&self.clock);
+        let entry = CacheEntry::new(valid_until(expires_in, now), value, now);
+        self.store.lock().await.insert(key.to_owned(), entry);
+    }
+
+    pub async fn delete<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = read_clock(&self.clock);
+        let entry = self.store.lock().await.remove(key)?;
+        entry
+            .is_valid(now)
+            .then(|| Arc::unwrap_or_clone(entry.value))
+    }
+
+    /// Counts valid entries.
+    pub async fn len(&self) -> usize {
+        let now = read_clock(&self.clock);
+        let store = self.store.lock().await;
+        store.values().filter(|entry| entry.is_valid(now)).count()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Removes all the expired entries and returns their keys. There is no
+    /// reaper, so expired entries stay in the store until purged, deleted or
+    /// overwritten.
+    pub async fn purge_expired(&self) -> Vec<K> {
+        let now = read_clock(&self.clock);
+        let mut store = self.store.lock().await;
+        let expired: Vec<K> = store
+            .iter()
+            .filter(|(_, entry)| !entry.is_valid(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            store.remove(key);
+        }
+        expired
+    }
+}
+
+pub struct ZCache {}
+
+impl ZCache {
+    pub async fn fetch<F, Fut>(
+        key: &str,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE.fetch(key, expires_in, f).await
+    }
+
+    pub async fn try_fetch<F, Fut, E>(
+        key: &str,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<ZEntry, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<ZEntry, E>>,
+        E: From<ZCacheError>,
+    {
+        DEFAULT_INSTANCE.try_fetch(key, expires_in, f).await
+    }
+
+    pub async fn fetch_retry<F, Fut>(
+        key: &str,
+        expires_in: Option<Duration>,
+        retries: usize,
+        backoff: Duration,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE
+            .fetch_retry(key, expires_in, retries, backoff, f)
+            .await
+    }
+
+    pub async fn fetch_timeout<F, Fut>(
+        key: &str,
+        expires_in: Option<Duration>,
+        timeout: Duration,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE
+            .fetch_timeout(key, expires_in, timeout, f)
+            .await
+    }
+
+    pub async fn fetch_with_negative<F, Fut>(
+        key: &str,
+        expires_in: Option<Duration>,
+        negative_ttl: Duration,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE
+            .fetch_with_negative(key, expires_in, negative_ttl, f)
+            .await
+    }
+
+    pub async fn fetch_or_stale<F, Fut>(
+        key: &str,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE.fetch_or_stale(key, expires_in, f).await
+    }
+
+    pub async fn fetch_with_breaker<F, Fut>(
+        key: &str,
+        expires_in: Option<Duration>,
+        breaker: BreakerConfig,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE
+            .fetch_with_breaker(key, expires_in, breaker, f)
+            .await
+    }
+
+    pub async fn fetch_keyed<A, F, Fut>(
+        args: &A,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        A: Hash + ?Sized,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<ZEntry>>,
+    {
+        DEFAULT_INSTANCE.fetch_keyed(args, expires_in, f).await
+    }
+
+    pub async fn fetch_swr<F, Fut>(
+        key: &str,
+        fresh_for: Duration,
+        stale_for: Duration,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Option<ZEntry>> + Send + 'static,
+    {
+        DEFAULT_INSTANCE
+            .fetch_swr(key, fresh_for, stale_for, f)
+            .await
+    }
+
+    pub async fn fetch_refresh_ahead<F, Fut>(
+        key: &str,
+        expires_in: Duration,
+        refresh_window: Duration,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<ZEntry>> + Send + 'static,
+    {
+        DEFAULT_INSTANCE
+            .fetch_refresh_ahead(key, expires_in, refresh_window, f)
+            .await
+    }
+
+    pub fn fetch_sync<F>(
+        key: &str,
+        expires_in: Option<Duration>,
+        f: F,
+    ) -> Result<ZEntry, ZCacheError>
+    where
+        F: FnOnce() -> Option<ZEntry>,
+    {
+        DEFAULT_INSTANCE.fetch_sync(key, expires_in, f)
+    }
+
+    pub fn get_or_compute<F>(key: &str, expires_in: Option<Duration>, f: F) -> ZEntry
+    where
+        F: FnOnce() -> ZEntry,
+    {
+        DEFAULT_INSTANCE.get_or_compute(key, expires_in, f)
+    }
+
+    pub fn read(key: &str) -> Option<ZEntry> {
+        DEFAULT_INSTANCE.read(key)
+    }
+
+    pub fn read_typed<T: TryFrom<ZEntry>>(key: &str) -> Option<T> {
+        DEFAULT_INSTANCE.read_typed(key)
+    }
+
+    pub fn read_or(key: &str, default: ZEntry) -> ZEntry {
+        DEFAULT_INSTANCE.read_or(key, default)
+    }
+
+    pub fn read_or_else<F: FnOnce() -> ZEntry>(key: &str, f: F) -> ZEntry {
+        DEFAULT_INSTANCE.read_or_else(key, f)
+    }
+
+    pub fn read_arc(key: &str) -> Option<Arc<ZEntry>> {
+        DEFAULT_INSTANCE.read_arc(key)
+    }
+
+    pub fn mget(keys: &[&str]) -> Vec<Option<ZEntry>> {
+        DEFAULT_INSTANCE.mget(keys)
+    }
+
+    pub fn contains_key(key: &str) -> bool {
+        DEFAULT_INSTANCE.contains_key(key)
+    }
+
+    pub fn keys() -> Vec<String> {
+        DEFAULT_INSTANCE.keys()
+    }
+
+    pub fn len() -> usize {
+        DEFAULT_INSTANCE.len()
+    }
+
+    pub fn is_empty() -> bool {
+        DEFAULT_INSTANCE.is_empty()
+    }
+
+    /// Preallocates the global store for `capacity` entries, same as
+    /// `Cache::with_capacity`. It must be called before any other `ZCache`
+    /// method, and returns `false` without doing anything if the store is
+    /// already in use. Only the first of concurrent calls succeeds.
+    pub fn init_with_capacity(capacity: usize) -> bool {
+        if INITIAL_CAPACITY.set(capacity).is_err() {
+            return false;
+        }
+        Lazy::force(&DEFAULT_INSTANCE);
+        true
+    }
+
+    pub fn capacity() -> usize {
+        DEFAULT_INSTANCE.capacity()
+    }
+
+    pub fn ttl(key: &str) -> Option<Duration> {
+        DEFAULT_INSTANCE.ttl(key)
+    }
+
+    pub fn count_by_type() -> HashMap<&'static str, usize> {
+        DEFAULT_INSTANCE.count_by_type()
+    }
+
+    pub fn read_all_by_type(kind: ZEntryKind) -> Vec<(String, ZEntry)> {
+        DEFAULT_INSTANCE.read_all_by_type(kind)
+    }
+
+    pub fn peek(key: &str) -> Option<ZEntry> {
+        DEFAULT_INSTANCE.peek(key)
+    }
+
+    pub fn snapshot_entries() -> Vec<(String, ZEntry, Option<Duration>)> {
+        DEFAULT_INSTANCE.snapshot_entries()
+    }
+
+    pub fn export() -> HashMap<String, ZEntry> {
+        DEFAULT_INSTANCE.export()
+    }
+
+    pub async fn import(map: HashMap<String, ZEntry>, expires_in: Option<Duration>) {
+        DEFAULT_INSTANCE.import(map, expires_in).await
+    }
+
+    pub fn entry_info(key: &str) -> Option<EntryInfo> {
+        DEFAULT_INSTANCE.entry_info(key)
+    }
+
+    pub async fn write(key: &str, value: ZEntry, expires_in: Option<Duration>) {
+        DEFAULT_INSTANCE.write(key, value, expires_in).await
+    }
+
+    pub async fn try_write(
+        key: &str,
+        value: ZEntry,
+        expires_in: Option<Duration>,
+    ) -> Result<(), ZCacheError> {
+        DEFAULT_INSTANCE.try_write(key, value, expires_in).await
+    }
+
+    pub async fn write_until(key: &str, value: ZEntry, expires_at: SystemTime) {
+        DEFAULT_INSTANCE.write_until(key, value, expires_at).await
+    }
+
+    pub async fn write_jittered(
+        key: &str,
+        value: ZEntry,
+        base_ttl: Duration,
+        jitter_fraction: f64,
+    ) {
+        DEFAULT_INSTANCE
+            .write_jittered(key, value, base_ttl, jitter_fraction)
+            .await
+    }
+
+    pub fn set_jitter_seed(seed: u64) {
+        DEFAULT_INSTANCE.set_jitter_seed(seed)
+    }
+
+    pub async fn write_nx(key: &str, value: ZEntry, expires_in: Option<Duration>) -> bool {
+        DEFAULT_INSTANCE.write_nx(key, value, expires_in).await
+    }
+
+    pub fn get_or_insert(key: &str, default: ZEntry, expires_in: Option<Duration>) -> ZEntry {
+        DEFAULT_INSTANCE.get_or_insert(key, default, expires_in)
+    }
+
+    pub fn update<F: FnOnce(&mut ZEntry)>(key: &str, f: F) -> bool {
+        DEFAULT_INSTANCE.update(key, f)
+    }
+
+    pub fn compare_and_swap(
+        key: &str,
+        expected: &ZEntry,
+        new: ZEntry,
+        expires_in: Option<Duration>,
+    ) -> bool {
+        DEFAULT_INSTANCE.compare_and_swap(key, expected, new, expires_in)
+    }
+
+    pub fn read_versioned(key: &str) -> Option<(ZEntry, u64)> {
+        DEFAULT_INSTANCE.read_versioned(key)
+    }
+
+    pub fn write_if_version(
+        key: &str,
+        value: ZEntry,
+        expected_version: u64,
+        expires_in: Option<Duration>,
+    ) -> bool {
+        DEFAULT_INSTANCE.write_if_version(key, value, expected_version, expires_in)
+    }
+
+    pub async fn mset(entries: Vec<(String, ZEntry, Option<Duration>)>) {
+        DEFAULT_INSTANCE.mset(entries).await
+    }
+
+    pub async fn write_many(
+        entries: Vec<(String, ZEntry, Option<Duration>)>,
+    ) -> Vec<Option<ZEntry>> {
+        DEFAULT_INSTANCE.write_many(entries).await
+    }
+
+    pub async fn warm<F, Fut>(entries: Vec<(String, Option<Duration>)>, loader: F)
+    where
+        F: FnOnce(Vec<String>) -> Fut,
+        Fut: Future<Output = Vec<Option<ZEntry>>>,
+    {
+        DEFAULT_INSTANCE.warm(entries, loader).await
+    }
+
+    pub fn touch(key: &str, expires_in: Option<Duration>) -> bool {
+        DEFAULT_INSTANCE.touch(key, expires_in)
+    }
+
+    pub fn expire_now(key: &str) -> bool {
+        DEFAULT_INSTANCE.expire_now(key)
+    }
+
+    pub async fn write_tagged(
+        key: &str,
+        value: ZEntry,
+        expires_in: Option<Duration>,
+        tags: &[&str],
+    ) {
+        DEFAULT_INSTANCE
+            .write_tagged(key, value, expires_in, tags)
+            .await
+    }
+
+    pub fn entry(key: &str) -> EntryKey<'static, ZEntry> {
+        DEFAULT_INSTANCE.entry(key)
+    }
+
+    pub fn subscribe() -> broadcast::Receiver<CacheEvent> {
+        DEFAULT_INSTANCE.subscribe()
+    }
+
+    pub fn invalidate_tag(tag: &str) -> usize {
+        DEFAULT_INSTANCE.invalidate_tag(tag)
+    }
+
+    pub fn on_evict<F: Fn(&str, &ZEntry) + Send + Sync + 'static>(f: F) {
+        DEFAULT_INSTANCE.on_evict(move |key: &String, value| f(key, value))
+    }
+
+    pub fn on_evict_async<F, Fut>(f: F)
+    where
+        F: Fn(String, ZEntry) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        DEFAULT_INSTANCE.on_evict_async(f)
+    }
+
+    pub fn rename(from: &str, to: &str) -> bool {
+        DEFAULT_INSTANCE.rename(from, to)
+    }
+
+    pub fn delete(key: &str) -> Option<ZEntry> {
+        DEFAULT_INSTANCE.delete(key)
+    }
+
+    pub fn take(key: &str) -> Option<ZEntry> {
+        DEFAULT_INSTANCE.take(key)
+    }
+
+    pub fn delete_many(keys: &[&str]) -> usize {
+        DEFAULT_INSTANCE.delete_many(keys)
+    }
+
+    pub fn increment(key: &str, by: i64) -> Result<i64, ZCacheError> {
+        DEFAULT_INSTANCE.increment(key, by)
+    }
+
+    pub fn add_float(key: &str, by: f64) -> Result<f64, ZCacheError> {
+        DEFAULT_INSTANCE.add_float(key, by)
+    }
+
+    pub fn toggle(key: &str) -> Result<bool, ZCacheError> {
+        DEFAULT_INSTANCE.toggle(key)
+    }
+
+    pub fn append(key: &str, suffix: &str) -> Result<String, ZCacheError> {
+        DEFAULT_INSTANCE.append(key, suffix)
+    }
+
+    pub fn stats() -> CacheStats {
+        DEFAULT_INSTANCE.stats()
+    }
+
+    pub fn raw_stats() -> RawStats {
+        DEFAULT_INSTANCE.raw_stats()
+    }
+
+    pub fn stats_for_namespace(ns: &str) -> CacheStats {
+        DEFAULT_INSTANCE.stats_for_namespace(ns)
+    }
+
+    pub fn reset_stats() {
+        DEFAULT_INSTANCE.reset_stats()
+    }
+
+    pub fn set_max_entries(max_entries: Option<usize>) {
+        DEFAULT_INSTANCE.set_max_entries(max_entries)
+    }
+
+    pub fn set_max_bytes(max_bytes: Option<usize>) {
+        DEFAULT_INSTANCE.set_max_bytes(max_bytes)
+    }
+
+    pub fn set_max_entry_bytes(max_entry_bytes: Option<usize>) {
+        DEFAULT_INSTANCE.set_max_entry_bytes(max_entry_bytes)
+    }
+
+    pub fn estimated_bytes() -> usize {
+        DEFAULT_INSTANCE.estimated_bytes()
+    }
+
+    pub fn purge_expired() -> Vec<String> {
+        DEFAULT_INSTANCE.purge_expired()
+    }
+
+    pub fn set_clock(clock: Arc<dyn Clock>) {
+        DEFAULT_INSTANCE.set_clock(clock)
+    }
+
+    pub fn configure(config: ZCacheConfig) -> Option<ReaperHandle> {
+        DEFAULT_INSTANCE.configure(config)
+    }
+
+    pub fn start_reaper(interval: Duration) -> ReaperHandle {
+        DEFAULT_INSTANCE.start_reaper(interval)
+    }
+
+    pub fn reap_once() -> usize {
+        DEFAULT_INSTANCE.reap_once()
+    }
+
+    pub fn reap_batch(max: usize) -> usize {
+        DEFAULT_INSTANCE.reap_batch(max)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn save_to_path(path: &Path) -> std::io::Result<()> {
+        DEFAULT_INSTANCE.save_to_path(path)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn load_from_path(path: &Path) -> std::io::Result<()> {
+        DEFAULT_INSTANCE.load_from_path(path)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn persist_on_shutdown(path: PathBuf) {
+        DEFAULT_INSTANCE.persist_on_shutdown(path)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn flush() -> std::io::Result<()> {
+        DEFAULT_INSTANCE.flush()
+    }
+
+    #[cfg(feature = "bincode")]
+    pub fn save_to_path_bin(path: &Path) -> std::io::Result<()> {
+        DEFAULT_INSTANCE.save_to_path_bin(path)
+    }
+
+    #[cfg(feature = "bincode")]
+    pub fn load_from_path_bin(path: &Path) -> std::io::Result<()> {
+        DEFAULT_INSTANCE.load_from_path_bin(path)
+    }
+
+    pub fn namespace(ns: &str) -> Namespace<'static, ZEntry> {
+        DEFAULT_INSTANCE.namespace(ns)
+    }
+
+    pub fn clear_prefix(prefix: &str) -> usize {
+        DEFAULT_INSTANCE.clear_prefix(prefix)
+    }
+
+    pub fn scan_prefix(prefix: &str) -> Vec<(String, ZEntry)> {
+        DEFAULT_INSTANCE.scan_prefix(prefix)
+    }
+
+    pub fn clear_namespace(ns: &str) -> usize {
+        DEFAULT_INSTANCE.clear_namespace(ns)
+    }
+
+    pub fn clear() -> usize {
+        DEFAULT_INSTANCE.clear()
+    }
+
+    pub fn clear_if<F: Fn(&str, &ZEntry) -> bool>(predicate: F) -> usize {
+        DEFAULT_INSTANCE.clear_if(|key: &String, value| predicate(key, value))
+    }
+
+    /// Returns the global instance registered under `name`, creating it on
+    /// first use. Calls with the same name share the instance, which is
+    /// independent of the default store and of other names.
+    pub fn named(name: &str) -> Arc<ZCacheInstance> {
+        lock(&NAMED_INSTANCES)
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Removes the instance registered under `name` and returns whether it
+    /// existed. Handles returned by `named` keep working, but later calls
+    /// create a new empty instance.
+    pub fn drop_named(name: &str) -> bool {
+        lock(&NAMED_INSTANCES).remove(name).is_some()
+    }
+}
+
+// Write lock of the shard of one key, or of all the shards.
+enum StoreWriteGuard<'a, K, V, S> {
+    Shard {
+        shard: RwLockWriteGuard<'a, ShardMap<K, V, S>>,
+        written: Written<K>,
+    },
+    All {
+        store: &'a Store<K, V, S>,
+        shards: Vec<RwLockWriteGuard<'a, ShardMap<K, V, S>>>,
+        written: Written<K>,
+    },
+}
+
+// Inserts through a `StoreWriteGuard`, handled by `release`. The last key
+// inserted while a limit is set is kept, so it's not evicted, together with
+// the tags of overwritten entries the new ones don't have.
+struct Written<K> {
+    inserted: Option<K>,
+    untagged: Vec<(K, Vec<String>)>,
+}
+
+impl<K> Default for Written<K> {
+    fn default() -> Self {
+        Self {
+            inserted: None,
+            untagged: Vec::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V, S: BuildHasher> StoreWriteGuard<'_, K, V, S> {
+    fn shard_mut<Q: Hash + ?Sized>(
+        &mut self,
+        key: &Q,
+    ) -> (&mut ShardMap<K, V, S>, &mut Written<K>) {
+        match self {
+            StoreWriteGuard::Shard { shard, written } => (shard, written),
+            StoreWriteGuard::All {
+                store,
+                shards,
+                written,
+            } => (&mut shards[store.index(key)], written),
+        }
+    }
+
+    fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut CacheEntry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_mut(key).0.get_mut(key)
+    }
+
+    fn resize<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_mut(key).0.resize(key)
+    }
+
+    fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, CacheEntry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_mut(key).0.remove_entry(key)
+    }
+
+    fn insert(&mut self, key: K, entry: CacheEntry<V>) {
+        let (shard, written) = self.shard_mut(&key);
+        let stale: Vec<String> = shard.get(&key).map_or_else(Vec::new, |previous| {
+            previous
+                .tags
+                .iter()
+                .filter(|tag| !entry.tags.contains(tag))
+                .cloned()
+                .collect()
+        });
+        if !stale.is_empty() {
+            written.untagged.push((key.clone(), stale));
+        }
+        if shard.totals.is_ordered() {
+            written.inserted = Some(key.clone());
+        }
+        shard.insert(key, entry);
+    }
+
+    // Releases the locks and returns what was written.
+    fn into_written(self) -> Written<K> {
+        match self {
+            StoreWriteGuard::Shard { written, .. } => written,
+            StoreWriteGuard::All { written, .. } => written,
+        }
+    }
+}
+
+struct InFlightGuard<'a, K: Eq + Hash> {
+    in_flight: &'a InFlightLocks<K>,
+    key: K,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl<'a, K: Eq + Hash + Clone> InFlightGuard<'a, K> {
+    async fn acquire(in_flight: &'a InFlightLocks<K>, key: K) -> InFlightGuard<'a, K> {
+        let lock = lock(in_flight).entry(key.clone()).or_default().clone();
+        Self {
+            in_flight,
+            key,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Drop for InFlightGuard<'_, K> {
+    fn drop(&mut self) {
+        let mut in_flight = lock(self.in_flight);
+        self.guard.take();
+        if let Some(lock) = in_flight.get(&self.key) {
+            if Arc::strong_count(lock) == 1 {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+struct ComputeGuard<'a, K: Eq + Hash> {
+    computing: &'a ComputingKeys<K>,
+    key: K,
+}
+
+impl<'a, K: Eq + Hash + Clone> ComputeGuard<'a, K> {
+    fn acquire(computing: &'a ComputingKeys<K>, key: K) -> ComputeGuard<'a, K> {
+        let (keys, done) = computing;
+        let mut keys = lock(keys);
+        while keys.contains(&key) {
+            keys = done.wait(keys).unwrap_or_else(PoisonError::into_inner);
+        }
+        keys.insert(key.clone());
+        Self { computing, key }
+    }
+}
+
+// Also releases the key if `f` panics, so waiting threads compute it.
+impl<K: Eq + Hash> Drop for ComputeGuard<'_, K> {
+    fn drop(&mut self) {
+        let (keys, done) = self.computing;
+        lock(keys).remove(&self.key);
+        done.notify_all();
+    }
+}
+
+// Cache state is not critical, so a panic in another thread while holding
+// a lock should not make the cache unusable.
+fn read_shard<K, V, S>(shard: &Shard<K, V, S>) -> RwLockReadGuard<'_, ShardMap<K, V, S>> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_shard<K, V, S>(shard: &Shard<K, V, S>) -> RwLockWriteGuard<'_, ShardMap<K, V, S>> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Shards are always locked in the same order to avoid deadlocks.
+fn read_all<K, V, S>(store: &Store<K, V, S>) -> Vec<RwLockReadGuard<'_, ShardMap<K, V, S>>> {
+    store.iter().map(read_shard).collect()
+}
+
+fn write_all<K, V, S>(store: &Store<K, V, S>) -> Vec<RwLockWriteGuard<'_, ShardMap<K, V, S>>> {
+    store.iter().map(write_shard).collect()
+}
+
+fn read_clock(clock: &SharedClock) -> u128 {
+    clock
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .now_in_millis()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn remove_expired<K, V, S>(cache: &mut ShardMap<K, V, S>, now: u128) -> Removed<K, V>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    let expired: Vec<K> = cache
+        .iter()
+        .filter(|(_, entry)| !entry.is_valid(now))
+        .map(|(key, _)| key.clone())
+        .collect();
+    expired
+        .into_iter()
+        .filter_map(|key| cache.remove_entry(&key))
+        .collect()
+}
+
+// Replaces the entry with a refreshed value, keeping its tags and refresher,
+// and returns the stored value. If the refresh failed or returned a value
+// over the size limit, it can be retried.
+fn refresh_entry<K: Eq + Hash + Clone, V, S: BuildHasher>(
+    store: &Store<K, V, S>,
+    clock: &SharedClock,
+    entry_limit: &EntryLimit<V>,
+    key: &K,
+    value: Option<V>,
+    fresh_for: Duration,
+    stale_for: Duration,
+) -> Option<Arc<V>> {
+    let value = value.filter(|value| entry_limit.check(value).is_ok());
+    let mut cache = write_shard(store.shard(key));
+    let entry = cache.get_mut(key)?;
+    match value {
+        Some(value) => {
+            let now = read_clock(clock);
+            let mut refreshed = CacheEntry::with_stale_window(value, fresh_for, stale_for, now);
+            refreshed.tags = std::mem::take(&mut entry.tags);
+            refreshed.refresher = entry.refresher.take();
+            let value = refreshed.value.clone();
+            cache.insert(key.clone(), refreshed);
+            Some(value)
+        }
+        None => {
+            entry.refreshing.store(false, Ordering::Release);
+            None
+        }
+    }
+}
+
+fn reap<K, V, S>(
+    store: &Store<K, V, S>,
+    now: u128,
+    expirations: &AtomicU64,
+    tags: &TagIndex<K>,
+    on_evict: &EvictSlot<K, V>,
+    events: &broadcast::Sender<CacheEvent<K>>,
+) -> usize
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    let mut reaped = 0;
+    for shard in store.iter() {
+        let removed = remove_expired(&mut write_shard(shard), now);
+        reaped += removed.len();
+        expirations.fetch_add(removed.len() as u64, Ordering::Relaxed);
+        for (key, entry) in &removed {
+            untag(tags, key, &entry.tags);
+        }
+        if let Some(callback) = evict_callback(on_evict) {
+            for (key, entry) in &removed {
+                callback(key, &entry.value);
+            }
+        }
+        if events.receiver_count() > 0 {
+            for (key, _) in removed {
+                let _ = events.send(CacheEvent::Expired { key });
+            }
+        }
+    }
+    reaped
+}
+
+fn untag<K: Eq + Hash>(index: &TagIndex<K>, key: &K, tags: &[String]) {
+    if tags.is_empty() {
+        return;
+    }
+    let mut index = lock(index);
+    for tag in tags {
+        if let Some(keys) = index.get_mut(tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                index.remove(tag);
+            }
+        }
+    }
+}
+
+// Cached `None` results and breakers are otherwise only removed when their
+// key is fetched again, so keys fetched once would be kept forever.
+fn prune_loaders<K>(negative: &NegativeKeys<K>, breakers: &Breakers<K>, now: u128) {
+    lock(negative).retain(|_, valid_until| is_valid(*valid_until, now));
+    lock(breakers).retain(|_, state| state.forget_at.max(state.open_until) > now);
+}
+
+fn entry_size<V: HeapSize>(key: &str, value: &V) -> usize {
+    key.len() + std::mem::size_of::<V>() + value.heap_size()
+}
+
+fn evict_callback<K, V>(slot: &EvictSlot<K, V>) -> Option<EvictCallback<K, V>> {
+    slot.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+// `DefaultHasher::new` uses fixed keys, so keys are the same in every run of
+// one build, but its algorithm may change between Rust versions.
+fn keyed<A: Hash + ?Sized>(args: &A) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::any::type_name::<A>().hash(&mut hasher);
+    args.hash(&mut hasher);
+    format!("keyed:{:016x}", hasher.finish())
+}
+
+fn next_tick() -> u64 {
+    ACCESS_TICK.fetch_add(1, Ordering::Relaxed)
+}
+
+fn next_version() -> u64 {
+    VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+// Expiry too far in the future to represent is treated as no expiry.
+fn valid_until(expires_in: Option<Duration>, now: u128) -> u128 {
+    expires_in
+        .and_then(|duration| now.checked_add(duration.as_millis()))
+        .unwrap_or(0)
+}
+
+fn remaining(valid_until: u128, now: u128) -> Duration {
+    let millis = u64::try_from(valid_until.saturating_sub(now)).unwrap_or(u64::MAX);
+    Duration::from_millis(millis)
+}
+
+fn is_valid(valid_until: u128, now: u128) -> bool {
+    valid_until == 0 || valid_until > now
+}
+
+fn now_in_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards!")
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Mul;
+    use std::thread::sleep;
+
+    use super::*;
+
+    // Tests share the global store, so they must not run concurrently.
+    static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[tokio::test]
+    async fn read_write_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        let cacheable = ZEntry::Int(1);
+        let one_second = Duration::from_secs(1);
+        ZCache::write("key1", cacheable, Some(one_second)).await;
+        let result = ZCache::read("key1");
+
+        match result {
+            Some(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Unexpected value"),
+        }
+
+        sleep(one_second.mul(2));
+        let result = ZCache::read("key1");
+
+        if result.is_some() {
+            panic!("Entry should be expired!");
+        }
+
+        let cacheable = ZEntry::Text("cached text".to_string());
+        ZCache::write("key2", cacheable, None).await;
+        sleep(one_second.mul(2));
+        let result = ZCache::read("key2");
+        match result {
+            Some(ZEntry::Text(value)) => assert_eq!(value, "cached text".to_string()),
+            _ => panic!("Unexpected value"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        let cacheable = ZEntry::Int(1);
+        let result = ZCache::fetch("key1", None, || async { Some(cacheable.clone()) }).await;
+
+        match result {
+            Ok(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Unexpected value"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_expiry_works() -> Result<(), ZCacheError> {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        let clock = Arc::new(MockClock::new());
+        ZCache::set_clock(clock.clone());
+        let started = std::time::Instant::now();
+        let cacheable = ZEntry::Int(1);
+        let one_second = Duration::from_secs(1);
+        let result = ZCache::fetch("key1", Some(one_second), || async {
+            Some(cacheable.clone())
+        })
+        .await;
+        match result {
+            Ok(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Unexpected value"),
+        }
+
+        match ZCache::fetch("key1", Some(one_second), || async {
+            Some(cacheable.clone())
+        })
+        .await?
+        {
+            ZEntry::Int(value) => value,
+            _ => panic!("Unexpected type"),
+        };
+
+        clock.advance(one_second.mul(2));
+        let result = ZCache::read("key1");
+        ZCache::set_clock(Arc::new(SystemClock));
+
+        if result.is_some() {
+            panic!("Entry should be expired!");
+        }
+        assert!(started.elapsed() < one_second);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        let one_second = Duration::from_secs(1);
+        ZCache::write("key1", ZEntry::Int(1), Some(one_second)).await;
+
+        match ZCache::delete("key1") {
+            Some(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Unexpected value"),
+        }
+        assert!(ZCache::read("key1").is_none());
+        assert!(ZCache::delete("key1").is_none());
+
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_millis(10))).await;
+        sleep(Duration::from_millis(20));
+        assert!(ZCache::delete("key2").is_none());
+    }
+
+    #[tokio::test]
+    async fn take_works() {
+        let cache = ZCacheInstance::new();
+        cache
+            .write("token", ZEntry::Text("secret".into()), None)
+            .await;
+
+        assert_eq!(cache.take("token"), Some(ZEntry::Text("secret".into())));
+        assert_eq!(cache.take("token"), None);
+        assert!(!cache.contains_key("token"));
+    }
+
+    #[tokio::test]
+    async fn delete_many_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(1)))
+            .await;
+        cache.write("key5", ZEntry::Int(5), None).await;
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(cache.delete_many(&["key1", "key2", "key4"]), 2);
+        assert_eq!(cache.delete_many(&["key1", "key3"]), 0);
+        assert_eq!(cache.keys(), vec!["key5".to_string()]);
+        assert_eq!(cache.stats().expirations, 1);
+    }
+
+    #[tokio::test]
+    async fn clear_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.write("key3", ZEntry::Int(3), None).await;
+        cache
+            .write("key4", ZEntry::Int(4), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(cache.clear(), 3);
+        assert!(cache.is_empty());
+        assert!(cache.purge_expired().is_empty());
+        assert_eq!(cache.clear(), 0);
+    }
+
+    #[tokio::test]
+    async fn contains_key_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Text("cached text".to_string()), None).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_millis(10))).await;
+
+        assert!(ZCache::contains_key("key1"));
+        assert!(ZCache::contains_key("key2"));
+        assert!(!ZCache::contains_key("key3"));
+
+        sleep(Duration::from_millis(20));
+        assert!(!ZCache::contains_key("key2"));
+    }
+
+    #[tokio::test]
+    async fn keys_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(1), None).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_secs(1))).await;
+        ZCache::write("key3", ZEntry::Int(3), Some(Duration::from_millis(10))).await;
+        sleep(Duration::from_millis(20));
+
+        let mut keys = ZCache::keys();
+        keys.sort();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys, vec!["key1".to_string(), "key2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn len_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        assert!(ZCache::is_empty());
+
+        let ttl = Some(Duration::from_millis(10));
+        ZCache::write("key1", ZEntry::Int(1), ttl).await;
+        ZCache::write("key2", ZEntry::Int(2), ttl).await;
+        assert_eq!(ZCache::len(), 2);
+        assert!(!ZCache::is_empty());
+
+        sleep(Duration::from_millis(20));
+        assert_eq!(ZCache::len(), 0);
+        assert!(ZCache::is_empty());
+    }
+
+    #[tokio::test]
+    async fn ttl_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(1), None).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_secs(10))).await;
+        ZCache::write("key3", ZEntry::Int(3), Some(Duration::from_millis(10))).await;
+        sleep(Duration::from_millis(20));
+
+        assert_eq!(ZCache::ttl("key1"), Some(Duration::MAX));
+        let ttl = ZCache::ttl("key2").expect("Entry should be valid!");
+        assert!(ttl > Duration::from_secs(9) && ttl <= Duration::from_secs(10));
+        assert_eq!(ZCache::ttl("key3"), None);
+        assert_eq!(ZCache::ttl("key4"), None);
+    }
+
+    #[tokio::test]
+    async fn with_capacity_works() {
+        let cache = ZCacheInstance::with_capacity(1000);
+        let capacity = cache.capacity();
+        assert!(capacity >= 1000);
+        for index in 0..1000 {
+            cache
+                .write(&format!("key{}", index), index.into(), None)
+                .await;
+        }
+        assert_eq!(cache.len(), 1000);
+        assert_eq!(cache.read("key999"), Some(ZEntry::Int(999)));
+        assert_eq!(cache.capacity(), capacity);
+
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        assert!(!ZCache::init_with_capacity(1000));
+    }
+
+    #[tokio::test]
+    async fn mock_clock_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::from_secs(10)))
+            .await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.ttl("key1"), Some(Duration::from_secs(5)));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.read("key1"), None);
+        assert_eq!(cache.read("key2"), Some(ZEntry::Int(2)));
+        assert_eq!(cache.purge_expired(), vec!["key1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn write_max_duration_works() {
+        let cache = ZCacheInstance::new();
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::MAX))
+            .await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        assert!(cache.touch("key2", Some(Duration::MAX)));
+
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+        assert_eq!(cache.read("key2"), Some(ZEntry::Int(2)));
+        assert!(cache
+            .ttl("key1")
+            .is_some_and(|ttl| ttl > Duration::from_secs(1)));
+        assert!(cache.purge_expired().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_or_insert_works() {
+        let cache = ZCacheInstance::new();
+        assert_eq!(
+            cache.get_or_insert("key1", ZEntry::Int(1), None),
+            ZEntry::Int(1)
+        );
+        assert_eq!(
+            cache.get_or_insert("key1", ZEntry::Int(2), None),
+            ZEntry::Int(1)
+        );
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+
+        cache
+            .write("key2", ZEntry::Int(1), Some(Duration::from_millis(10)))
+            .await;
+        sleep(Duration::from_millis(20));
+        assert_eq!(
+            cache.get_or_insert("key2", ZEntry::Int(2), None),
+            ZEntry::Int(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidate_tag_works() {
+        let cache = ZCacheInstance::new();
+        for key in ["key1", "key2", "key3"] {
+            cache
+                .write_tagged(key, ZEntry::Int(1), None, &["user:1"])
+                .await;
+        }
+        cache
+            .write_tagged("key4", ZEntry::Int(4), None, &["user:2"])
+            .await;
+        cache.write("key5", ZEntry::Int(5), None).await;
+
+        assert_eq!(cache.invalidate_tag("user:1"), 3);
+        assert!(!cache.contains_key("key1"));
+        assert!(!cache.contains_key("key2"));
+        assert!(!cache.contains_key("key3"));
+        assert!(cache.contains_key("key4"));
+        assert!(cache.contains_key("key5"));
+        assert_eq!(cache.invalidate_tag("user:1"), 0);
+
+        cache.delete("key4");
+        assert!(lock(&cache.tags).is_empty());
+
+        cache
+            .write_tagged("key6", ZEntry::Int(6), None, &["user:3"])
+            .await;
+        cache.write("key6", ZEntry::Int(7), None).await;
+        assert!(lock(&cache.tags).is_empty());
+        assert_eq!(cache.invalidate_tag("user:3"), 0);
+        assert_eq!(cache.read("key6"), Some(ZEntry::Int(7)));
+
+        let clock = Arc::new(MockClock::new());
+        cache.set_clock(clock.clone());
+        cache
+            .write_tagged(
+                "key7",
+                ZEntry::Int(7),
+                Some(Duration::from_secs(1)),
+                &["tag"],
+            )
+            .await;
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.reap_once(), 1);
+        assert!(lock(&cache.tags).is_empty());
+    }
+
+    #[tokio::test]
+    async fn entry_builder_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache
+            .entry("key1")
+            .value(ZEntry::Int(1))
+            .ttl(Duration::from_secs(60))
+            .write()
+            .await;
+        cache
+            .entry("key2")
+            .value(ZEntry::Int(2))
+            .tags(&["tag"])
+            .write()
+            .await;
+
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+        assert_eq!(cache.ttl("key1"), Some(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(cache.read("key1"), None);
+        assert_eq!(cache.ttl("key2"), Some(Duration::MAX));
+        assert_eq!(cache.invalidate_tag("tag"), 1);
+    }
+
+    #[tokio::test]
+    async fn fetch_retry_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        let backoff = Duration::from_millis(1);
+        let attempts = AtomicU64::new(0);
+        let value = cache
+            .fetch_retry("key1", None, 3, backoff, || async {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                (attempt == 2).then_some(ZEntry::Int(1))
+            })
+            .await?;
+        assert_eq!(value, ZEntry::Int(1));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+
+        let result = cache
+            .fetch_retry("key2", None, 1, backoff, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                None
+            })
+            .await;
+        assert!(matches!(result, Err(ZCacheError::FetchError(key)) if key == "key2"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn try_fetch_works() {
+        #[derive(Debug, PartialEq)]
+        struct BackendError(&'static str);
+
+        impl From<ZCacheError> for BackendError {
+            fn from(_: ZCacheError) -> Self {
+                BackendError("too large")
+            }
+        }
+
+        let cache = ZCacheInstance::new();
+        let result = cache
+            .try_fetch("key1", None, || async { Err(BackendError("timeout")) })
+            .await;
+        assert_eq!(result, Err(BackendError("timeout")));
+        assert!(!cache.contains_key("key1"));
+
+        let result = cache
+            .try_fetch("key1", None, || async {
+                Ok::<_, BackendError>(ZEntry::Int(1))
+            })
+            .await;
+        assert_eq!(result, Ok(ZEntry::Int(1)));
+        let result = cache
+            .try_fetch("key1", None, || async { Err(BackendError("unused")) })
+            .await;
+        assert_eq!(result, Ok(ZEntry::Int(1)));
+
+        cache.set_max_entry_bytes(Some(4));
+        let result = cache
+            .try_fetch("key2", None, || async {
+                Ok::<_, BackendError>(ZEntry::Text("too long".to_string()))
+            })
+            .await;
+        assert_eq!(result, Err(BackendError("too large")));
+        assert!(!cache.contains_key("key2"));
+    }
+
+    #[tokio::test]
+    async fn fetch_timeout_works() {
+        let cache = ZCacheInstance::new();
+        let timeout = Duration::from_millis(10);
+        let result = cache
+            .fetch_timeout("key1", None, timeout, || async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Some(ZEntry::Int(1))
+            })
+            .await;
+        assert!(matches!(result, Err(ZCacheError::Timeout(key)) if key == "key1"));
+        assert!(!cache.contains_key("key1"));
+
+        let result = cache
+            .fetch_timeout("key1", None, timeout, || async { Some(ZEntry::Int(2)) })
+            .await;
+        assert_eq!(result.ok(), Some(ZEntry::Int(2)));
+    }
+
+    #[tokio::test]
+    async fn fetch_with_negative_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        let negative_ttl = Duration::from_secs(5);
+        let calls = AtomicU64::new(0);
+        let fetch = || {
+            cache.fetch_with_negative("key1", None, negative_ttl, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                None
+            })
+        };
+
+        assert!(matches!(fetch().await, Err(ZCacheError::FetchError(_))));
+        assert!(matches!(fetch().await, Err(ZCacheError::FetchError(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.read("key1"), None);
+
+        clock.advance(negative_ttl);
+        assert!(fetch().await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        cache.purge_expired();
+        assert_eq!(lock(&cache.negative).len(), 1);
+        clock.advance(negative_ttl);
+        cache.reap_batch(10);
+        assert_eq!(lock(&cache.negative).len(), 1);
+        cache.purge_expired();
+        assert!(lock(&cache.negative).is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_with_breaker_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        let breaker = BreakerConfig::new(2, Duration::from_secs(10));
+        let calls = AtomicUsize::new(0);
+        let fetch = |value: Option<ZEntry>| {
+            cache.fetch_with_breaker("key1", None, breaker, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                value
+            })
+        };
+
+        for _ in 0..2 {
+            let result = fetch(None).await;
+            assert!(matches!(result, Err(ZCacheError::FetchError(_))));
+        }
+        let result = fetch(Some(ZEntry::Int(1))).await;
+        assert!(matches!(result, Err(ZCacheError::CircuitOpen(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(10));
+        let result = fetch(None).await;
+        assert!(matches!(result, Err(ZCacheError::FetchError(_))));
+        let result = fetch(Some(ZEntry::Int(1))).await;
+        assert!(matches!(result, Err(ZCacheError::CircuitOpen(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(fetch(Some(ZEntry::Int(1))).await.unwrap(), ZEntry::Int(1));
+        assert!(lock(&cache.breakers).is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let result = cache
+            .fetch_with_breaker("key2", None, breaker, || async { None })
+            .await;
+        assert!(result.is_err());
+        cache.reap_once();
+        assert_eq!(lock(&cache.breakers).len(), 1);
+        clock.advance(Duration::from_secs(10));
+        cache.reap_once();
+        assert!(lock(&cache.breakers).is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_or_stale_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(2));
+
+        let result = cache.fetch_or_stale("key1", None, || async { None }).await;
+        assert_eq!(result.ok(), Some(ZEntry::Int(1)));
+        let result = cache.fetch_or_stale("key2", None, || async { None }).await;
+        assert!(matches!(result, Err(ZCacheError::FetchError(key)) if key == "key2"));
+
+        let result = cache
+            .fetch_or_stale("key1", None, || async { Some(ZEntry::Int(2)) })
+            .await;
+        assert_eq!(result.ok(), Some(ZEntry::Int(2)));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(2)));
+    }
+
+    #[tokio::test]
+    async fn fetch_keyed_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        let calls = AtomicU64::new(0);
+        for (n, expected_calls) in [(3, 1), (3, 1), (4, 2)] {
+            let value = cache
+                .fetch_keyed(&n, None, || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Some(ZEntry::Int(n * n))
+                })
+                .await?;
+            assert_eq!(value, ZEntry::Int(n * n));
+            assert_eq!(calls.load(Ordering::SeqCst), expected_calls);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_ne!(keyed(&3i64), keyed(&3i32));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_refresh_ahead_works() -> Result<(), ZCacheError> {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        let loads = Arc::new(AtomicU64::new(0));
+        let loader = {
+            let loads = loads.clone();
+            move || {
+                let loads = loads.clone();
+                async move { Some(ZEntry::Int(loads.fetch_add(1, Ordering::SeqCst) as i64)) }
+            }
+        };
+        let expires_in = Duration::from_secs(10);
+        let refresh_window = Duration::from_secs(2);
+
+        let value = cache
+            .fetch_refresh_ahead("key1", expires_in, refresh_window, loader)
+            .await?;
+        assert_eq!(value, ZEntry::Int(0));
+
+        clock.advance(Duration::from_secs(7));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(0)));
+        tokio::task::yield_now().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(0)));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(0)));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while cache.peek("key1") != Some(ZEntry::Int(1)) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("Entry should be refreshed!");
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.ttl("key1"), Some(expires_in));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+        tokio::task::yield_now().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_swr_works() -> Result<(), ZCacheError> {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        let fresh_for = Duration::from_secs(10);
+        let stale_for = Duration::from_secs(60);
+
+        let value = cache
+            .fetch_swr("key1", fresh_for, stale_for, || async {
+                Some(ZEntry::Int(1))
+            })
+            .await?;
+        assert_eq!(value, ZEntry::Int(1));
+
+        clock.advance(Duration::from_secs(20));
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let value = cache
+            .fetch_swr("key1", fresh_for, stale_for, || async { rx.await.ok() })
+            .await?;
+        assert_eq!(value, ZEntry::Int(1));
+        let value = cache
+            .fetch_swr("key1", fresh_for, stale_for, || async {
+                panic!("Refresh should be already running!")
+            })
+            .await?;
+        assert_eq!(value, ZEntry::Int(1));
+
+        tx.send(ZEntry::Int(2)).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while cache.read("key1") != Some(ZEntry::Int(2)) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("Entry should be refreshed!");
+
+        clock.advance(Duration::from_secs(100));
+        let value = cache
+            .fetch_swr("key1", fresh_for, stale_for, || async {
+                Some(ZEntry::Int(3))
+            })
+            .await?;
+        assert_eq!(value, ZEntry::Int(3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn null_entry_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Null, None).await;
+
+        assert_eq!(cache.read("key1"), Some(ZEntry::Null));
+        assert!(cache.read("key1").is_some_and(|entry| entry.is_null()));
+        assert_eq!(cache.read("key2"), None);
+    }
+
+    #[tokio::test]
+    async fn on_evict_works() {
+        let cache = ZCacheInstance::new();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let observed = evicted.clone();
+        cache.on_evict(move |key, value| {
+            lock(&observed).push((key.to_string(), value.clone()));
+        });
+
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key1", ZEntry::Int(2), None).await;
+        cache.delete("key1");
+        assert_eq!(*lock(&evicted), vec![("key1".to_string(), ZEntry::Int(2))]);
+
+        cache.set_max_entries(Some(1));
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.write("key3", ZEntry::Int(3), None).await;
+        cache
+            .write("key4", ZEntry::Int(4), Some(Duration::from_millis(0)))
+            .await;
+        cache.purge_expired();
+        assert_eq!(
+            lock(&evicted)[1..],
+            [
+                ("key2".to_string(), ZEntry::Int(2)),
+                ("key3".to_string(), ZEntry::Int(3)),
+                ("key4".to_string(), ZEntry::Int(4)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribe_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        let mut events = cache.subscribe();
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.delete("key1");
+        cache.increment("key2", 1).unwrap();
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+        cache.reap_once();
+        cache.clear();
+
+        let key = |key: &str| key.to_string();
+        for expected in [
+            CacheEvent::Written { key: key("key1") },
+            CacheEvent::Deleted { key: key("key1") },
+            CacheEvent::Written { key: key("key2") },
+            CacheEvent::Written { key: key("key3") },
+            CacheEvent::Expired { key: key("key3") },
+            CacheEvent::Cleared,
+        ] {
+            assert_eq!(events.try_recv(), Ok(expected));
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn subscribe_lagging_works() {
+        let cache = ZCacheInstance::new();
+        let mut events = cache.subscribe();
+        for index in 0..EVENT_CAPACITY + 1 {
+            cache
+                .write(&format!("key{}", index), ZEntry::Int(1), None)
+                .await;
+        }
+
+        assert_eq!(
+            events.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(1))
+        );
+        assert_eq!(
+            events.try_recv(),
+            Ok(CacheEvent::Written {
+                key: "key1".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn on_evict_async_works() {
+        let cache = ZCacheInstance::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        cache.on_evict_async(move |key, value| {
+            let tx = tx.clone();
+            async move {
+                tokio::task::yield_now().await;
+                tx.send((key, value)).unwrap();
+            }
+        });
+
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.delete("key1");
+        let evicted = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert_eq!(evicted, Ok(Some(("key1".to_string(), ZEntry::Int(1)))));
+    }
+
+    #[tokio::test]
+    async fn rename_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache
+            .write("from", ZEntry::Int(1), Some(Duration::from_secs(10)))
+            .await;
+        cache.write("to", ZEntry::Int(2), None).await;
+        clock.advance(Duration::from_secs(4));
+
+        assert!(cache.rename("from", "to"));
+        assert_eq!(cache.read("from"), None);
+        assert_eq!(cache.read("to"), Some(ZEntry::Int(1)));
+        assert_eq!(cache.ttl("to"), Some(Duration::from_secs(6)));
+        assert!(!cache.rename("from", "other"));
+
+        clock.advance(Duration::from_secs(6));
+        assert!(!cache.rename("to", "other"));
+    }
+
+    #[test]
+    fn display_works() {
+        assert_eq!(ZEntry::Int(5).to_string(), "5");
+        assert_eq!(ZEntry::Float(1.5).to_string(), "1.5");
+        assert_eq!(ZEntry::Text("hi".into()).to_string(), "hi");
+        assert_eq!(ZEntry::Bool(true).to_string(), "true");
+        assert_eq!(ZEntry::Bytes(vec![0, 171, 255]).to_string(), "0x00abff");
+        assert_eq!(ZEntry::Null.to_string(), "null");
+        assert_eq!(
+            ZEntry::List(vec![ZEntry::Int(1), ZEntry::Text("hi".into())]).to_string(),
+            "[1, hi]"
+        );
+        let map = HashMap::from([
+            ("b".to_string(), ZEntry::Int(2)),
+            ("a".to_string(), ZEntry::List(vec![])),
+        ]);
+        assert_eq!(ZEntry::Map(map).to_string(), "{a: [], b: 2}");
+    }
+
+    #[test]
+    fn conversions_work() {
+        assert_eq!(ZEntry::from(42i64), ZEntry::Int(42));
+        assert_eq!(ZEntry::from(1.5), ZEntry::Float(1.5));
+        assert_eq!(
+            ZEntry::from("text".to_string()),
+            ZEntry::Text("text".into())
+        );
+        assert_eq!(ZEntry::from("text"), ZEntry::Text("text".into()));
+        assert_eq!(ZEntry::from(true), ZEntry::Bool(true));
+
+        assert_eq!(i64::try_from(ZEntry::Int(42)).ok(), Some(42));
+        assert_eq!(f64::try_from(ZEntry::Float(1.5)).ok(), Some(1.5));
+        assert_eq!(
+            String::try_from(ZEntry::Text("text".into())).ok(),
+            Some("text".to_string())
+        );
+        assert_eq!(bool::try_from(ZEntry::Bool(true)).ok(), Some(true));
+
+        match i64::try_from(ZEntry::Text("42".into())) {
+            Err(ZCacheError::TypeMismatch { expected, found }) => {
+                assert_eq!(expected, "Int");
+                assert_eq!(found, "Text");
+            }
+            _ => panic!("Expected type mismatch!"),
+        }
+    }
+
+    #[test]
+    fn heap_size_works() {
+        assert_eq!(ZEntry::Int(1).heap_size(), 0);
+        assert_eq!(ZEntry::Bool(true).heap_size(), 0);
+        assert_eq!(ZEntry::Text("a".repeat(1000)).heap_size(), 1000);
+        assert_eq!(ZEntry::Text(String::with_capacity(100)).heap_size(), 100);
+        let list = ZEntry::List(vec![ZEntry::Bytes(Vec::with_capacity(10))]);
+        assert_eq!(list.heap_size(), std::mem::size_of::<ZEntry>() + 10);
+    }
+
+    #[tokio::test]
+    async fn peak_bytes_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+        let small = cache.estimated_bytes();
+        assert_eq!(cache.stats().peak_bytes, small);
+
+        cache
+            .write("key2", ZEntry::Text("a".repeat(1000)), None)
+            .await;
+        let peak = cache.estimated_bytes();
+        assert!(peak > small + 1000);
+        cache.delete("key2");
+        assert_eq!(cache.estimated_bytes(), small);
+        assert_eq!(cache.stats().peak_bytes, peak);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats().peak_bytes, 0);
+        cache.write("key3", ZEntry::Int(3), None).await;
+        assert_eq!(cache.stats().peak_bytes, cache.estimated_bytes());
+    }
+
+    #[tokio::test]
+    async fn set_max_entry_bytes_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        cache.set_max_entry_bytes(Some(100));
+        let large = ZEntry::Text("a".repeat(101));
+
+        let result = cache.try_write("key1", large.clone(), None).await;
+        assert!(matches!(
+            result,
+            Err(ZCacheError::EntryTooLarge {
+                size: 101,
+                limit: 100
+            })
+        ));
+        cache.write("key1", large.clone(), None).await;
+        assert!(!cache.contains_key("key1"));
+        let result = cache
+            .fetch("key1", None, || async { Some(large.clone()) })
+            .await;
+        assert!(matches!(result, Err(ZCacheError::EntryTooLarge { .. })));
+        assert!(!cache.contains_key("key1"));
+
+        cache
+            .try_write("key2", ZEntry::Text("a".repeat(100)), None)
+            .await?;
+        assert!(cache.contains_key("key2"));
+
+        let text = ZEntry::Text("a".repeat(100));
+        cache
+            .write_tagged("key3", large.clone(), None, &["tag"])
+            .await;
+        cache.entry("key3").value(large.clone()).write().await;
+        assert!(!cache.write_nx("key3", large.clone(), None).await);
+        assert_eq!(cache.get_or_insert("key3", large.clone(), None), large);
+        assert!(!cache.compare_and_swap("key2", &text, large.clone(), None));
+        let (_, version) = cache.read_versioned("key2").unwrap();
+        assert!(!cache.write_if_version("key2", large.clone(), version, None));
+        let expires_at = SystemTime::now() + Duration::from_secs(10);
+        cache.write_until("key3", large.clone(), expires_at).await;
+        cache.mset(vec![("key3".into(), large.clone(), None)]).await;
+        let previous = cache
+            .write_many(vec![
+                ("key2".into(), large.clone(), None),
+                ("key4".into(), ZEntry::Int(4), None),
+            ])
+            .await;
+        assert_eq!(previous, vec![None, None]);
+        let result = cache.append("key2", "a");
+        assert!(matches!(result, Err(ZCacheError::EntryTooLarge { .. })));
+        let result = cache
+            .fetch_swr("key3", Duration::from_secs(1), Duration::from_secs(1), {
+                let large = large.clone();
+                || async { Some(large) }
+            })
+            .await;
+        assert!(matches!(result, Err(ZCacheError::EntryTooLarge { .. })));
+
+        assert!(!cache.contains_key("key3"));
+        assert!(cache.contains_key("key4"));
+        assert_eq!(cache.read("key2"), Some(text));
+        assert_eq!(cache.stats().rejections, 14);
+
+        cache.set_max_entry_bytes(Some(0));
+        cache.try_write("key1", large, None).await?;
+        assert!(cache.contains_key("key1"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn estimated_bytes_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        let base = std::mem::size_of::<ZEntry>();
+        assert_eq!(cache.estimated_bytes(), 0);
+
+        cache.write("key1", ZEntry::Int(1), None).await;
+        assert_eq!(cache.estimated_bytes(), 4 + base);
+        cache
+            .write(
+                "key2",
+                ZEntry::Text("a".repeat(1000)),
+                Some(Duration::from_secs(1)),
+            )
+            .await;
+        assert_eq!(cache.estimated_bytes(), 2 * (4 + base) + 1000);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.estimated_bytes(), 4 + base);
+        cache.purge_expired();
+        assert_eq!(cache.estimated_bytes(), 4 + base);
+        assert_eq!(cache.stats().peak_bytes, 2 * (4 + base) + 1000);
+
+        #[derive(Clone)]
+        struct Blob(Vec<u8>);
+        impl HeapSize for Blob {
+            fn heap_size(&self) -> usize {
+                self.0.len()
+            }
+        }
+        let cache = Cache::<Blob>::new();
+        cache.write("key1", Blob(vec![0; 10]), None).await;
+        assert_eq!(cache.stats().peak_bytes, 0);
+        let size = 4 + std::mem::size_of::<Blob>() + 10;
+        assert_eq!(cache.estimated_bytes(), size);
+        cache.write("key2", Blob(vec![0; 10]), None).await;
+        assert_eq!(cache.stats().peak_bytes, 2 * size);
+    }
+
+    #[tokio::test]
+    async fn set_max_bytes_works() {
+        let cache = ZCacheInstance::new();
+        let small = entry_size("key1", &ZEntry::Int(1));
+        let text = ZEntry::Text("x".repeat(1000));
+        let big = entry_size("big", &text);
+        cache.set_max_bytes(Some(big + 2 * small - 1));
+
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.write("key3", ZEntry::Int(3), None).await;
+        cache.read("key1");
+        cache.write("big", text.clone(), None).await;
+
+        assert!(cache.contains_key("key1"));
+        assert!(!cache.contains_key("key2"));
+        assert!(!cache.contains_key("key3"));
+        assert_eq!(cache.read("big"), Some(text));
+
+        // The total is kept up to date without summing the entries.
+        assert_eq!(cache.store.bytes(), big + small);
+        // The peak is taken after evictions.
+        assert_eq!(cache.stats().peak_bytes, big + small);
+        cache.update("big", |value| *value = ZEntry::Int(0));
+        assert_eq!(
+            cache.store.bytes(),
+            entry_size("big", &ZEntry::Int(0)) + small
+        );
+        cache.clear();
+        assert_eq!(cache.store.bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn lfu_eviction_works() {
+        let cache = ZCacheInstance::new();
+        cache.configure(
+            ZCacheConfig::new()
+                .max_entries(2)
+                .eviction_policy(EvictionPolicy::Lfu),
+        );
+        cache.write("hot", ZEntry::Int(1), None).await;
+        cache.write("cold", ZEntry::Int(2), None).await;
+        for _ in 0..10 {
+            cache.read("hot");
+        }
+        cache.read("cold");
+
+        cache.write("key3", ZEntry::Int(3), None).await;
+        assert!(cache.contains_key("hot"));
+        assert!(!cache.contains_key("cold"));
+        assert!(cache.contains_key("key3"));
+    }
+
+    #[tokio::test]
+    async fn lfu_aging_works() {
+        let cache = ZCacheInstance::new();
+        cache.configure(
+            ZCacheConfig::new()
+                .max_entries(2)
+                .eviction_policy(EvictionPolicy::Lfu),
+        );
+        cache.write("hot", ZEntry::Int(1), None).await;
+        for _ in 0..3 {
+            cache.read("hot");
+        }
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.read("key1");
+        cache.write("key2", ZEntry::Int(2), None).await;
+        assert!(!cache.contains_key("key1"));
+
+        // Reads of key2 count on top of the evicted key1.
+        cache.read("key2");
+        cache.read("key2");
+        cache.write("key3", ZEntry::Int(3), None).await;
+        assert!(!cache.contains_key("hot"));
+        assert!(cache.contains_key("key2"));
+        assert!(cache.contains_key("key3"));
+    }
+
+    #[tokio::test]
+    async fn eviction_order_works() {
+        let cache = ZCacheInstance::new();
+        cache.set_max_entries(Some(2));
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.read("key1");
+        assert!(cache.expire_now("key1"));
+        cache.write("key3", ZEntry::Int(3), None).await;
+        assert!(!cache.contains_key("key1"));
+        assert!(cache.contains_key("key2"));
+        assert_eq!(cache.stats().evictions, 0);
+        assert_eq!(cache.stats().expirations, 1);
+
+        // The order is built again from all the entries.
+        cache.set_max_entries(None);
+        cache.write("key4", ZEntry::Int(4), None).await;
+        cache.write("key5", ZEntry::Int(5), None).await;
+        cache.set_max_entries(Some(2));
+        cache.write("key6", ZEntry::Int(6), None).await;
+        let mut keys = cache.keys();
+        keys.sort();
+        assert_eq!(keys, ["key5", "key6"]);
+        assert_eq!(cache.stats().evictions, 3);
+    }
+
+    #[tokio::test]
+    async fn clear_if_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Bool(true), None).await;
+        cache.write("key2", ZEntry::Bool(false), None).await;
+        cache.write("key3", ZEntry::Int(1), None).await;
+        cache.write("key4", ZEntry::Text("text".into()), None).await;
+        cache
+            .write("key5", ZEntry::Bool(true), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(cache.clear_if(|_, value| value.as_bool().is_some()), 2);
+        let mut keys = cache.keys();
+        keys.sort();
+        assert_eq!(keys, ["key3", "key4"]);
+        assert_eq!(cache.clear_if(|key, _| key == "key4"), 1);
+        assert_eq!(cache.raw_stats().total, 2);
+    }
+
+    #[tokio::test]
+    async fn clear_prefix_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("session:abc", ZEntry::Int(1), None).await;
+        cache.write("session:def", ZEntry::Int(2), None).await;
+        cache.write("config:ghi", ZEntry::Int(3), None).await;
+
+        assert_eq!(cache.clear_prefix("session:"), 2);
+        assert!(!cache.contains_key("session:abc"));
+        assert!(!cache.contains_key("session:def"));
+        assert_eq!(cache.read("config:ghi"), Some(ZEntry::Int(3)));
+    }
+
+    #[tokio::test]
+    async fn scan_prefix_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("user:1", ZEntry::Int(1), None).await;
+        cache.write("user:2", ZEntry::Int(2), None).await;
+        cache
+            .write("user:3", ZEntry::Int(3), Some(Duration::from_secs(1)))
+            .await;
+        cache.write("config:x", ZEntry::Int(4), None).await;
+        clock.advance(Duration::from_secs(1));
+
+        let mut users = cache.scan_prefix("user:");
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            users,
+            vec![
+                ("user:1".to_string(), ZEntry::Int(1)),
+                ("user:2".to_string(), ZEntry::Int(2)),
+            ]
+        );
+        assert!(cache.scan_prefix("session:").is_empty());
+    }
+
+    #[tokio::test]
+    async fn entry_info_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::from_secs(10)))
+            .await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        let created_at = clock.now_in_millis();
+
+        clock.advance(Duration::from_millis(50));
+        cache.read("key1");
+        let info = cache.entry_info("key1").expect("Entry should be valid!");
+        assert_eq!(info.created_at, created_at);
+        assert_eq!(info.last_accessed, created_at + 50);
+        assert_eq!(info.valid_until, Some(created_at + 10_000));
+
+        let info = cache.entry_info("key2").expect("Entry should be valid!");
+        assert_eq!(info.last_accessed, info.created_at);
+        assert_eq!(info.valid_until, None);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.entry_info("key1"), None);
+        assert_eq!(cache.entry_info("key3"), None);
+    }
+
+    #[tokio::test]
+    async fn peek_works() {
+        let cache = ZCacheInstance::new();
+        cache.set_max_entries(Some(2));
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        for _ in 0..3 {
+            assert_eq!(cache.peek("key1"), Some(ZEntry::Int(1)));
+        }
+        cache.write("key3", ZEntry::Int(3), None).await;
+        assert_eq!(cache.peek("key1"), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 0,
+                misses: 0,
+                evictions: 1,
+                expirations: 0,
+                entries: 2,
+                peak_bytes: 2 * (4 + std::mem::size_of::<ZEntry>()),
+                rejections: 0
+            }
+        );
+
+        cache.read("key2");
+        cache.write("key4", ZEntry::Int(4), None).await;
+        assert_eq!(cache.peek("key2"), Some(ZEntry::Int(2)));
+        assert_eq!(cache.peek("key3"), None);
+    }
+
+    #[tokio::test]
+    async fn backend_works() -> Result<(), ZCacheError> {
+        let backend = Arc::new(MemoryBackend::new());
+        let stored = |key: &str| {
+            let backend = backend.clone();
+            let key = key.to_string();
+            async move { backend.get(&key).await.map(|(value, _)| value) }
+        };
+        backend
+            .set(
+                &"key1".to_string(),
+                ZEntry::Int(1),
+                Some(Duration::from_secs(10)),
+            )
+            .await;
+        let cache = ZCacheInstance::with_backend(backend.clone());
+        let secs = Duration::from_secs;
+
+        assert_eq!(cache.read("key1"), None);
+        assert_eq!(cache.read_through("key1").await, Some(ZEntry::Int(1)));
+        assert_eq!(cache.peek("key1"), Some(ZEntry::Int(1)));
+        assert!(cache.ttl("key1").unwrap() <= Duration::from_secs(10));
+        assert_eq!(cache.read_through("key2").await, None);
+
+        cache.write("key2", ZEntry::Int(2), None).await;
+        assert_eq!(
+            backend.get(&"key2".to_string()).await,
+            Some((ZEntry::Int(2), None))
+        );
+        backend.set(&"key3".to_string(), ZEntry::Int(3), None).await;
+        let value = cache
+            .fetch("key3", None, || async { panic!("Backend should be used!") })
+            .await?;
+        assert_eq!(value, ZEntry::Int(3));
+        for key in ["key10", "key11", "key12"] {
+            backend.set(&key.to_string(), ZEntry::Int(10), None).await;
+        }
+        let value = cache
+            .try_fetch("key10", None, || async {
+                Err(ZCacheError::FetchError("key10".to_string()))
+            })
+            .await?;
+        assert_eq!(value, ZEntry::Int(10));
+        let value = cache
+            .fetch_timeout("key11", None, secs(1), || async { None })
+            .await?;
+        assert_eq!(value, ZEntry::Int(10));
+        let value = cache
+            .fetch_with_negative("key12", None, secs(1), || async { None })
+            .await?;
+        assert_eq!(value, ZEntry::Int(10));
+
+        cache.delete_through("key2").await;
+        assert_eq!(stored("key2").await, None);
+        assert_eq!(cache.read_through("key2").await, None);
+
+        cache.write("key4", ZEntry::Int(4), None).await;
+        cache.delete("key4");
+        let result = cache.fetch("key4", None, || async { None }).await;
+        assert!(matches!(result, Err(ZCacheError::FetchError(_))));
+        tokio::task::yield_now().await;
+        assert_eq!(stored("key4").await, None);
+
+        assert!(cache.write_nx("key5", ZEntry::Int(5), None).await);
+        cache
+            .fetch_swr("key6", secs(1), secs(1), || async { Some(ZEntry::Int(6)) })
+            .await?;
+        cache
+            .fetch_refresh_ahead("key7", secs(10), secs(1), || async { Some(ZEntry::Int(7)) })
+            .await?;
+        assert_eq!(stored("key5").await, Some(ZEntry::Int(5)));
+        assert_eq!(stored("key6").await, Some(ZEntry::Int(6)));
+        assert_eq!(stored("key7").await, Some(ZEntry::Int(7)));
+
+        assert!(cache.rename("key5", "key8"));
+        cache
+            .write_tagged("key9", ZEntry::Int(9), None, &["tag"])
+            .await;
+        cache.invalidate_tag("tag");
+        tokio::task::yield_now().await;
+        assert_eq!(stored("key5").await, None);
+        assert_eq!(stored("key8").await, Some(ZEntry::Int(5)));
+        assert_eq!(stored("key9").await, None);
+
+        cache.write("key13", ZEntry::Int(13), None).await;
+        assert!(cache.expire_now("key13"));
+        cache.increment("key14", 10)?;
+        cache.increment("key14", 4)?;
+        cache.write("key15", ZEntry::Int(15), Some(secs(1))).await;
+        assert!(cache.touch("key15", Some(secs(100))));
+        tokio::task::yield_now().await;
+        assert_eq!(stored("key13").await, None);
+        assert_eq!(stored("key14").await, Some(ZEntry::Int(14)));
+        let (_, ttl) = backend.get(&"key15".to_string()).await.unwrap();
+        assert!(ttl.unwrap() > secs(10));
+
+        cache.clear();
+        tokio::task::yield_now().await;
+        assert_eq!(stored("key8").await, None);
+        assert!(lock(&cache.backend_pending).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_by_type_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.write("key3", ZEntry::Text("text".into()), None).await;
+        cache
+            .write("key4", ZEntry::Bool(true), Some(Duration::from_millis(0)))
+            .await;
+
+        assert_eq!(
+            cache.count_by_type(),
+            HashMap::from([("Int", 2), ("Text", 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn read_all_by_type_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.write("key3", ZEntry::Bool(true), None).await;
+        cache
+            .write("key4", ZEntry::Int(4), Some(Duration::from_millis(0)))
+            .await;
+
+        let mut entries = cache.read_all_by_type(ZEntryKind::Int);
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                ("key1".to_string(), ZEntry::Int(1)),
+                ("key2".to_string(), ZEntry::Int(2)),
+            ]
+        );
+        assert!(cache.read_all_by_type(ZEntryKind::Text).is_empty());
+        assert_eq!(ZEntry::Bool(true).kind(), ZEntryKind::Bool);
+        assert_eq!(ZEntryKind::Bool.to_string(), "Bool");
+    }
+
+    #[tokio::test]
+    async fn export_import_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Text("hi".into()), None).await;
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+
+        let exported = cache.export();
+        assert_eq!(
+            exported,
+            HashMap::from([
+                ("key1".to_string(), ZEntry::Int(1)),
+                ("key2".to_string(), ZEntry::Text("hi".into())),
+            ])
+        );
+
+        cache.clear();
+        assert!(cache.is_empty());
+        cache
+            .import(exported.clone(), Some(Duration::from_secs(10)))
+            .await;
+        assert_eq!(cache.export(), exported);
+        assert_eq!(cache.ttl("key1"), Some(Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn snapshot_entries_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(10)))
+            .await;
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+
+        let mut snapshot = cache.snapshot_entries();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            snapshot,
+            vec![
+                ("key1".to_string(), ZEntry::Int(1), None),
+                (
+                    "key2".to_string(),
+                    ZEntry::Int(2),
+                    Some(Duration::from_secs(9))
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn touch_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(1), Some(Duration::from_millis(30))).await;
+
+        assert!(ZCache::touch("key1", Some(Duration::from_secs(1))));
+        sleep(Duration::from_millis(60));
+        match ZCache::read("key1") {
+            Some(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Entry should be still valid!"),
+        }
+
+        assert!(!ZCache::touch("key2", None));
+        ZCache::write("key3", ZEntry::Int(3), Some(Duration::from_millis(10))).await;
+        sleep(Duration::from_millis(20));
+        assert!(!ZCache::touch("key3", None));
+        assert!(ZCache::read("key3").is_none());
+    }
+
+    #[tokio::test]
+    async fn expire_now_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+
+        assert!(cache.expire_now("key1"));
+        assert_eq!(cache.read("key1"), None);
+        assert!(!cache.contains_key("key1"));
+        assert!(!cache.expire_now("key1"));
+        assert!(!cache.expire_now("key2"));
+
+        assert_eq!(cache.purge_expired(), vec!["key1".to_string()]);
+        assert_eq!(cache.purge_expired(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn bytes_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Bytes(vec![0u8, 255, 13]), None).await;
+
+        match ZCache::read("key1") {
+            Some(ZEntry::Bytes(value)) => assert_eq!(value, vec![0u8, 255, 13]),
+            _ => panic!("Unexpected value"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_works() {
+        let entries = vec![
+            (ZEntry::Int(1), r#"{"Int":1}"#),
+            (ZEntry::Float(1.5), r#"{"Float":1.5}"#),
+            (ZEntry::Text("text".to_string()), r#"{"Text":"text"}"#),
+            (ZEntry::Bool(true), r#"{"Bool":true}"#),
+            (ZEntry::Bytes(vec![0, 255]), r#"{"Bytes":[0,255]}"#),
+            (
+                ZEntry::List(vec![ZEntry::Int(1), ZEntry::List(vec![ZEntry::Bool(true)])]),
+                r#"{"List":[{"Int":1},{"List":[{"Bool":true}]}]}"#,
+            ),
+            (
+                ZEntry::Map(HashMap::from([("field".to_string(), ZEntry::Int(1))])),
+                r#"{"Map":{"field":{"Int":1}}}"#,
+            ),
+        ];
+
+        for (entry, expected) in entries {
+            let json = serde_json::to_string(&entry).unwrap();
+            assert_eq!(json, expected);
+            let parsed: ZEntry = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, entry);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_works() -> Result<(), serde_json::Error> {
+        let entries = [
+            (ZEntry::Int(-1), r#"{"Int":-1}"#),
+            (ZEntry::Float(0.25), r#"{"Float":0.25}"#),
+            (ZEntry::Text("hi".into()), r#"{"Text":"hi"}"#),
+            (ZEntry::Bool(false), r#"{"Bool":false}"#),
+            (ZEntry::Bytes(vec![1, 2]), r#"{"Bytes":[1,2]}"#),
+            (ZEntry::Null, r#""Null""#),
+        ];
+
+        for (entry, expected) in entries {
+            let json = entry.to_json()?;
+            assert_eq!(json, expected);
+            assert_eq!(ZEntry::from_json(&json)?, entry);
+        }
+        assert!(ZEntry::from_json(r#"{"Int":"1"}"#).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn save_to_path_works() -> std::io::Result<()> {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(1), None).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_secs(10))).await;
+        ZCache::write("key3", ZEntry::Int(3), Some(Duration::from_millis(10))).await;
+        sleep(Duration::from_millis(20));
+
+        let path = std::env::temp_dir().join("zcache_save_to_path_works.json");
+        ZCache::save_to_path(&path)?;
+        let file = std::fs::File::open(&path)?;
+        let mut snapshot: Vec<SnapshotEntry<String, ZEntry>> = serde_json::from_reader(file)?;
+        std::fs::remove_file(&path)?;
+
+        snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].key, "key1");
+        assert_eq!(snapshot[0].valid_until, 0);
+        assert_eq!(snapshot[1].key, "key2");
+        assert!(snapshot[1].valid_until > now_in_millis());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn load_from_path_works() -> std::io::Result<()> {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(1), Some(Duration::from_secs(10))).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_millis(50))).await;
+
+        let path = std::env::temp_dir().join("zcache_load_from_path_works.json");
+        ZCache::save_to_path(&path)?;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(10), None).await;
+        ZCache::write("key3", ZEntry::Int(3), None).await;
+        sleep(Duration::from_millis(60));
+        ZCache::load_from_path(&path)?;
+        std::fs::remove_file(&path)?;
+
+        match ZCache::read("key1") {
+            Some(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Unexpected value"),
+        }
+        let ttl = ZCache::ttl("key1").expect("Entry should be valid!");
+        assert!(ttl <= Duration::from_secs(10) && ttl > Duration::from_secs(9));
+        assert!(ZCache::read("key2").is_none());
+        assert!(ZCache::contains_key("key3"));
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn flush_works() -> std::io::Result<()> {
+        let path = std::env::temp_dir().join("zcache_flush_works.json");
+        let source = ZCacheInstance::new();
+        source.flush()?;
+        assert!(!path.exists());
+
+        source.persist_on_shutdown(path.clone());
+        source.write("key1", ZEntry::Int(1), None).await;
+        source
+            .write(
+                "key2",
+                ZEntry::Text("text".into()),
+                Some(Duration::from_secs(10)),
+            )
+            .await;
+        source.flush()?;
+        let target = ZCacheInstance::new();
+        target.load_from_path(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(target.read("key1"), Some(ZEntry::Int(1)));
+        assert_eq!(target.read("key2"), Some(ZEntry::Text("text".into())));
+        assert!(target
+            .ttl("key2")
+            .is_some_and(|ttl| ttl <= Duration::from_secs(10)));
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    #[tokio::test]
+    async fn save_to_path_bin_works() -> std::io::Result<()> {
+        let source = ZCacheInstance::new();
+        let entries = [
+            ("key1", ZEntry::Int(1)),
+            ("key2", ZEntry::Text("text".into())),
+            ("key3", ZEntry::List(vec![ZEntry::Float(1.5), ZEntry::Null])),
+            (
+                "key4",
+                ZEntry::Map(HashMap::from([("a".into(), ZEntry::Bytes(vec![1, 2]))])),
+            ),
+        ];
+        for (key, value) in &entries {
+            source
+                .write(*key, value.clone(), Some(Duration::from_secs(10)))
+                .await;
+        }
+
+        let path = std::env::temp_dir().join("zcache_save_to_path_bin_works.bin");
+        source.save_to_path_bin(&path)?;
+        let target = ZCacheInstance::new();
+        target.load_from_path_bin(&path)?;
+        std::fs::remove_file(&path)?;
+
+        let mut keys = target.keys();
+        keys.sort();
+        assert_eq!(keys, ["key1", "key2", "key3", "key4"]);
+        for (key, value) in entries {
+            assert_eq!(target.read(key), Some(value));
+            assert_eq!(
+                target.entry_info(key).map(|info| info.valid_until),
+                source.entry_info(key).map(|info| info.valid_until)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_entries_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::set_max_entries(Some(3));
+        ZCache::write("key1", ZEntry::Int(1), None).await;
+        ZCache::write("key2", ZEntry::Int(2), None).await;
+        ZCache::write("key3", ZEntry::Int(3), None).await;
+        ZCache::write("key4", ZEntry::Int(4), None).await;
+
+        assert_eq!(ZCache::len(), 3);
+        assert!(!ZCache::contains_key("key1"));
+
+        ZCache::read("key2");
+        ZCache::write("key3", ZEntry::Int(30), None).await;
+        ZCache::write("key5", ZEntry::Int(5), None).await;
+
+        assert_eq!(ZCache::len(), 3);
+        assert!(ZCache::contains_key("key2"));
+        assert!(!ZCache::contains_key("key4"));
+
+        ZCache::set_max_entries(None);
+    }
+
+    #[tokio::test]
+    async fn raw_stats_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(2));
+
+        assert_eq!(
+            cache.raw_stats(),
+            RawStats {
+                live: 1,
+                expired_present: 1,
+                total: 2
+            }
+        );
+        cache.purge_expired();
+        assert_eq!(cache.raw_stats().total, 1);
+    }
+
+    #[tokio::test]
+    async fn stats_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::reset_stats();
+        ZCache::write("key1", ZEntry::Int(1), None).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_millis(10))).await;
+        sleep(Duration::from_millis(20));
+
+        ZCache::read("key1");
+        ZCache::read("key1");
+        ZCache::read("key1");
+        ZCache::read("key2");
+        ZCache::read("key3");
+
+        let stats = ZCache::stats();
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 3,
+                misses: 2,
+                evictions: 0,
+                expirations: 0,
+                entries: 1,
+                peak_bytes: 2 * (4 + std::mem::size_of::<ZEntry>()),
+                rejections: 0
+            }
+        );
+        assert_eq!(stats.hit_rate(), 0.6);
+
+        ZCache::reset_stats();
+        assert_eq!(
+            ZCache::stats(),
+            CacheStats {
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+                expirations: 0,
+                entries: 1,
+                peak_bytes: 0,
+                rejections: 0
+            }
+        );
+        assert_eq!(ZCache::stats().hit_rate(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn reaper_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        ZCache::write("key1", ZEntry::Int(1), Some(Duration::from_millis(10))).await;
+        ZCache::write("key2", ZEntry::Int(2), Some(Duration::from_millis(10))).await;
+        let store_len = || {
+            DEFAULT_INSTANCE
+                .store
+                .iter()
+                .map(|shard| shard.read().unwrap().len())
+                .sum::<usize>()
+        };
+        assert_eq!(store_len(), 2);
+
+        let reaper = ZCache::start_reaper(Duration::from_millis(10));
+        let duplicate = ZCache::start_reaper(Duration::from_millis(10));
+        assert!(Arc::ptr_eq(&reaper.abort_handle, &duplicate.abort_handle));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(store_len(), 0);
+
+        reaper.stop();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        ZCache::write("key3", ZEntry::Int(3), Some(Duration::from_millis(10))).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(store_len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn fetch_single_flight_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let calls = calls.clone();
+                tokio::spawn(async move {
+                    ZCache::fetch("key1", None, || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Some(ZEntry::Int(1))
+                    })
+                    .await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(ZEntry::Int(value)) => assert_eq!(value, 1),
+                _ => panic!("Unexpected value"),
+            }
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(DEFAULT_INSTANCE.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn fetch_single_flight_failure_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+
+        let leader = tokio::spawn(async {
+            ZCache::fetch("key1", None, || async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                panic!("Loader failed!");
+            })
+            .await
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = ZCache::fetch("key1", None, || async { None }).await;
+        assert!(matches!(follower, Err(ZCacheError::FetchError(_))));
+        let follower = ZCache::fetch("key1", None, || async { Some(ZEntry::Int(1)) }).await;
+        assert!(matches!(follower, Ok(ZEntry::Int(1))));
+
+        assert!(leader.await.is_err());
+        assert!(DEFAULT_INSTANCE.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn async_cache_works() {
+        let cache = Arc::new(AsyncZCacheInstance::new());
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|index| {
+                let (cache, calls) = (cache.clone(), calls.clone());
+                tokio::spawn(async move {
+                    cache
+                        .write(&format!("key{}", index), ZEntry::Int(index), None)
+                        .await;
+                    cache
+                        .fetch("shared", None, || async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Some(ZEntry::Int(-1))
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().ok(), Some(ZEntry::Int(-1)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len().await, 51);
+        assert_eq!(cache.read("key7").await, Some(ZEntry::Int(7)));
+        assert_eq!(cache.delete("key7").await, Some(ZEntry::Int(7)));
+        assert_eq!(cache.read("key7").await, None);
+        assert!(lock(&cache.in_flight).is_empty());
+    }
+
+    #[tokio::test]
+    async fn async_cache_expiry_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = AsyncZCacheInstance::with_clock(clock.clone());
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::from_secs(1)))
+            .await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(cache.read("key1").await, None);
+        assert_eq!(cache.len().await, 1);
+        assert_eq!(cache.purge_expired().await, vec!["key1".to_string()]);
+        assert_eq!(cache.delete("key1").await, None);
+    }
+
+    #[tokio::test]
+    async fn eviction_and_expiration_stats_work() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.set_max_entries(Some(2));
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Int(2), None).await;
+        cache.write("key3", ZEntry::Int(3), None).await;
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.evictions, stats.expirations, stats.entries),
+            (1, 0, 2)
+        );
+
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+        cache.purge_expired();
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.evictions, stats.expirations, stats.entries),
+            (1, 1, 1)
+        );
+
+        cache
+            .write("key4", ZEntry::Int(4), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(1));
+        cache.write("key5", ZEntry::Int(5), None).await;
+        cache.write("key6", ZEntry::Int(6), None).await;
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.evictions, stats.expirations, stats.entries),
+            (2, 2, 2)
+        );
+
+        cache.reset_stats();
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.evictions, stats.expirations, stats.entries),
+            (0, 0, 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn instances_are_independent() {
+        let cache_a = ZCacheInstance::new();
+        let cache_b = ZCacheInstance::new();
+        cache_a.write("key1", ZEntry::Int(1), None).await;
+        cache_b.write("key1", ZEntry::Int(2), None).await;
+        cache_a.set_max_entries(Some(1));
+        cache_a.write("key2", ZEntry::Int(3), None).await;
+        cache_b.write("key2", ZEntry::Int(4), None).await;
+
+        assert!(cache_a.read("key1").is_none());
+        match cache_b.read("key1") {
+            Some(ZEntry::Int(value)) => assert_eq!(value, 2),
+            _ => panic!("Unexpected value"),
+        }
+        assert_eq!(cache_a.len(), 1);
+        assert_eq!(cache_b.len(), 2);
+        assert_eq!(
+            cache_a.stats(),
+            CacheStats {
+                hits: 0,
+                misses: 1,
+                evictions: 1,
+                expirations: 0,
+                entries: 1,
+                peak_bytes: 4 + std::mem::size_of::<ZEntry>(),
+                rejections: 0
+            }
+        );
+        assert_eq!(
+            cache_b.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 0,
+                evictions: 0,
+                expirations: 0,
+                entries: 2,
+                peak_bytes: 2 * (4 + std::mem::size_of::<ZEntry>()),
+                rejections: 0
+            }
+        );
+
+        cache_b.clear();
+        assert!(cache_b.is_empty());
+        assert!(cache_a.contains_key("key2"));
+    }
+
+    #[tokio::test]
+    async fn named_instances_work() {
+        ZCache::named("named_instances_works:a")
+            .write("key1", ZEntry::Int(1), None)
+            .await;
+
+        let a = ZCache::named("named_instances_works:a");
+        let b = ZCache::named("named_instances_works:b");
+        assert_eq!(a.read("key1"), Some(ZEntry::Int(1)));
+        assert_eq!(b.read("key1"), None);
+        b.write("key1", ZEntry::Int(2), None).await;
+        assert_eq!(a.read("key1"), Some(ZEntry::Int(1)));
+
+        assert!(ZCache::drop_named("named_instances_works:a"));
+        assert!(!ZCache::drop_named("named_instances_works:a"));
+        assert!(ZCache::named("named_instances_works:a").is_empty());
+        assert_eq!(a.read("key1"), Some(ZEntry::Int(1)));
+        ZCache::drop_named("named_instances_works:a");
+        ZCache::drop_named("named_instances_works:b");
+    }
+
+    #[tokio::test]
+    async fn concurrent_reads_work() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+
+        let _guard = cache.shard("key1").read().unwrap();
+        std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4).map(|_| scope.spawn(|| cache.read("key1"))).collect();
+            for reader in readers {
+                match reader.join().unwrap() {
+                    Some(ZEntry::Int(value)) => assert_eq!(value, 1),
+                    _ => panic!("Unexpected value"),
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn poisoned_lock_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+
+        let result = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _guard = cache.shard("key1").write().unwrap();
+                    panic!("Panic while holding the lock!");
+                })
+                .join()
+        });
+        assert!(result.is_err());
+        assert!(cache.shard("key1").is_poisoned());
+
+        match cache.read("key1") {
+            Some(ZEntry::Int(value)) => assert_eq!(value, 1),
+            _ => panic!("Unexpected value"),
+        }
+        cache.write("key2", ZEntry::Int(2), None).await;
+        let result = cache
+            .fetch("key3", None, || async { Some(ZEntry::Int(3)) })
+            .await;
+        assert!(matches!(result, Ok(ZEntry::Int(3))));
+        assert_eq!(cache.len(), 3);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn custom_hasher_works() {
+        #[derive(Default)]
+        struct FnvHasher(u64);
+
+        impl std::hash::Hasher for FnvHasher {
+            fn finish(&self) -> u64 {
+                self.0
+            }
+
+            fn write(&mut self, bytes: &[u8]) {
+                for byte in bytes {
+                    self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+                }
+            }
+        }
+
+        let cache: Cache<ZEntry, String, std::hash::BuildHasherDefault<FnvHasher>> =
+            Cache::with_hasher(Default::default());
+        for i in 0..100 {
+            cache
+                .write(&format!("key{}", i), ZEntry::Int(i), None)
+                .await;
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.read("key42"), Some(ZEntry::Int(42)));
+        assert_eq!(cache.delete("key42"), Some(ZEntry::Int(42)));
+        assert_eq!(cache.read("key42"), None);
+    }
+
+    #[tokio::test]
+    async fn generic_keys_work() {
+        let cache: Cache<ZEntry, (u64, u64)> = Cache::new();
+        cache.write(&(1, 2), ZEntry::Int(12), None).await;
+        cache.write(&(2, 1), ZEntry::Int(21), None).await;
+        assert_eq!(cache.read(&(1, 2)), Some(ZEntry::Int(12)));
+        assert_eq!(cache.read(&(1, 3)), None);
+        assert!(cache.rename(&(2, 1), &(3, 1)));
+        assert_eq!(cache.delete(&(3, 1)), Some(ZEntry::Int(21)));
+
+        cache.set_max_entries(Some(1));
+        cache.write(&(4, 4), ZEntry::Int(44), None).await;
+        assert_eq!(cache.keys(), vec![(4, 4)]);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[tokio::test]
+    async fn keys_without_debug_work() {
+        #[derive(PartialEq, Eq, Hash, Clone)]
+        struct Key(u64);
+
+        let cache: Cache<ZEntry, Key> = Cache::new();
+        cache.write(&Key(1), ZEntry::Int(1), None).await;
+        assert_eq!(cache.read(&Key(1)), Some(ZEntry::Int(1)));
+        assert_eq!(cache.delete(&Key(1)), Some(ZEntry::Int(1)));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_works() {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        // Collects the `op` and `outcome` fields of all events.
+        struct Collector(Arc<Mutex<Vec<String>>>);
+
+        impl Visit for &Collector {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                lock(&self.0).push(format!("{}={}", field.name(), value));
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+        }
+
+        impl tracing::Subscriber for Collector {
+            fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, _span: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, event: &tracing::Event<'_>) {
+                event.record(&mut &*self);
+            }
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let fields = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Collector(fields.clone()), || {
+            let cache = ZCacheInstance::new();
+            assert_eq!(cache.read("key1"), None);
+            cache
+                .fetch_sync("key1", None, || Some(ZEntry::Int(1)))
+                .unwrap();
+            assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+            assert_eq!(cache.delete("key1"), Some(ZEntry::Int(1)));
+        });
+        let fields = lock(&fields);
+        for field in ["outcome=miss", "op=write", "outcome=hit", "outcome=deleted"] {
+            assert!(fields.iter().any(|f| f == field), "{} not emitted", field);
+        }
+    }
+
+    #[tokio::test]
+    async fn sharded_writes_work() {
+        let cache = ZCacheInstance::new();
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let shards: std::collections::HashSet<usize> =
+            keys.iter().map(|key| cache.store.index(key)).collect();
+        assert_eq!(shards.len(), SHARD_COUNT);
+
+        std::thread::scope(|scope| {
+            for chunk in keys.chunks(100) {
+                let cache = &cache;
+                scope.spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    for (i, key) in chunk.iter().enumerate() {
+                        runtime.block_on(cache.write(key, ZEntry::Int(i as i64), None));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(cache.len(), 1000);
+        for chunk in keys.chunks(100) {
+            for (i, key) in chunk.iter().enumerate() {
+                match cache.read(key) {
+                    Some(ZEntry::Int(value)) => assert_eq!(value, i as i64),
+                    _ => panic!("Unexpected value"),
+                }
+            }
+        }
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn increment_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        assert_eq!(cache.increment("key1", 5)?, 5);
+        assert_eq!(cache.increment("key1", 5)?, 10);
+        assert_eq!(cache.increment("key1", 5)?, 15);
+        assert_eq!(cache.increment("key1", -20)?, -5);
+        assert_eq!(cache.ttl("key1"), Some(Duration::MAX));
+
+        cache
+            .write("key2", ZEntry::Int(1), Some(Duration::from_secs(10)))
+            .await;
+        assert_eq!(cache.increment("key2", 1)?, 2);
+        assert!(cache.ttl("key2").unwrap() <= Duration::from_secs(10));
+
+        cache.write("key3", ZEntry::Int(i64::MAX), None).await;
+        assert!(matches!(
+            cache.increment("key3", 1),
+            Err(ZCacheError::Overflow(_))
+        ));
+
+        cache.write("key4", ZEntry::Bool(true), None).await;
+        assert!(matches!(
+            cache.increment("key4", 1),
+            Err(ZCacheError::TypeMismatch {
+                expected: "Int",
+                found: "Bool"
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_works() -> Result<(), ZCacheError> {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        assert_eq!(cache.append("key1", "a")?, "a");
+        assert_eq!(cache.append("key1", "b")?, "ab");
+        assert_eq!(cache.ttl("key1"), Some(Duration::MAX));
+
+        cache
+            .write(
+                "key2",
+                ZEntry::Text("x".into()),
+                Some(Duration::from_secs(10)),
+            )
+            .await;
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(cache.append("key2", "y")?, "xy");
+        assert_eq!(cache.append("key2", "z")?, "xyz");
+        assert_eq!(cache.read("key2"), Some(ZEntry::Text("xyz".into())));
+        assert_eq!(cache.ttl("key2"), Some(Duration::from_secs(6)));
+
+        cache.write("key3", ZEntry::Int(1), None).await;
+        assert!(matches!(
+            cache.append("key3", "a"),
+            Err(ZCacheError::TypeMismatch {
+                expected: "Text",
+                found: "Int"
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_float_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        for _ in 0..3 {
+            cache.add_float("key1", 0.1)?;
+        }
+        match cache.read("key1") {
+            Some(ZEntry::Float(value)) => assert!((value - 0.3).abs() < 1e-9),
+            other => panic!("Unexpected entry {:?}", other),
+        }
+        assert_eq!(cache.ttl("key1"), Some(Duration::MAX));
+
+        assert_eq!(cache.add_float("key2", f64::MAX)?, f64::MAX);
+        assert_eq!(cache.add_float("key2", f64::MAX)?, f64::INFINITY);
+        assert!(cache.add_float("key2", f64::NAN)?.is_nan());
+
+        cache.write("key3", ZEntry::Int(1), None).await;
+        assert!(matches!(
+            cache.add_float("key3", 1.0),
+            Err(ZCacheError::TypeMismatch {
+                expected: "Float",
+                found: "Int"
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn toggle_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        assert!(cache.toggle("key1")?);
+        assert!(!cache.toggle("key1")?);
+        assert_eq!(cache.read("key1"), Some(ZEntry::Bool(false)));
+        assert_eq!(cache.ttl("key1"), Some(Duration::MAX));
+
+        cache.write("key2", ZEntry::Int(1), None).await;
+        assert!(matches!(
+            cache.toggle("key2"),
+            Err(ZCacheError::TypeMismatch {
+                expected: "Bool",
+                found: "Int"
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn accessors_work() {
+        let int = ZEntry::Int(1);
+        let float = ZEntry::Float(1.5);
+        let text = ZEntry::Text("text".to_string());
+        let bool = ZEntry::Bool(true);
+        let bytes = ZEntry::Bytes(vec![0, 255]);
+
+        assert_eq!(int.as_int(), Some(1));
+        assert_eq!(float.as_int(), None);
+        assert_eq!(float.as_float(), Some(1.5));
+        assert_eq!(int.as_float(), None);
+        assert_eq!(text.as_text(), Some("text"));
+        assert_eq!(bool.as_text(), None);
+        assert_eq!(bool.as_bool(), Some(true));
+        assert_eq!(text.as_bool(), None);
+        assert_eq!(bytes.as_bytes(), Some(&[0u8, 255][..]));
+        assert_eq!(int.as_bytes(), None);
+
+        assert_eq!(text.into_text(), Some("text".to_string()));
+        assert_eq!(bytes.clone().into_text(), None);
+        assert_eq!(bytes.into_bytes(), Some(vec![0, 255]));
+        assert_eq!(int.into_bytes(), None);
+    }
+
+    #[tokio::test]
+    async fn generic_cache_works() -> Result<(), ZCacheError> {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Foo {
+            id: u32,
+        }
+
+        let cache: Cache<Foo> = Cache::new();
+        cache.write("key1", Foo { id: 1 }, None).await;
+        assert_eq!(cache.read("key1"), Some(Foo { id: 1 }));
+
+        let value = cache
+            .fetch("key2", None, || async { Some(Foo { id: 2 }) })
+            .await?;
+        assert_eq!(value, Foo { id: 2 });
+        assert_eq!(cache.delete("key2"), Some(Foo { id: 2 }));
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn namespace_works() {
+        let _lock = TEST_LOCK.lock().await;
+        ZCache::clear();
+        let ns_a = ZCache::namespace("a");
+        let ns_b = ZCache::namespace("b");
+        ns_a.write("k", ZEntry::Int(1), None).await;
+        ns_b.write("k", ZEntry::Int(2), None).await;
+        ZCache::write("k", ZEntry::Int(3), None).await;
+
+        assert_eq!(ns_a.read("k").and_then(|e| e.as_int()), Some(1));
+        assert_eq!(ns_b.read("k").and_then(|e| e.as_int()), Some(2));
+        assert_eq!(ZCache::read("a:k").and_then(|e| e.as_int()), Some(1));
+
+        assert_eq!(ZCache::clear_namespace("a"), 1);
+        assert!(ns_a.read("k").is_none());
+        assert_eq!(ns_b.read("k").and_then(|e| e.as_int()), Some(2));
+        assert_eq!(ZCache::read("k").and_then(|e| e.as_int()), Some(3));
+
+        let value = ns_a
+            .fetch("k", None, || async { Some(ZEntry::Int(4)) })
+            .await;
+        assert!(matches!(value, Ok(ZEntry::Int(4))));
+        assert!(ns_b.delete("k").is_some());
+        assert_eq!(ns_a.clear(), 1);
+        assert_eq!(ZCache::len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_for_namespace_works() {
+        let cache = ZCacheInstance::new();
+        let ns_a = cache.namespace("a");
+        let ns_b = cache.namespace("b");
+        ns_a.write("k", ZEntry::Int(1), None).await;
+        ns_b.write("k", ZEntry::Int(2), None).await;
+        ns_b.write("l", ZEntry::Int(3), None).await;
+        cache.write("k", ZEntry::Int(4), None).await;
+
+        ns_a.read("k");
+        ns_a.read("missing");
+        ns_a.read("missing");
+        ns_b.read("k");
+        let _ = ns_b.fetch("l", None, || async { None }).await;
+        cache.read("k");
+        cache.read("missing");
+
+        let stats = cache.stats_for_namespace("a");
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+        let stats = cache.stats_for_namespace("b");
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 0, 2));
+        let stats = cache.stats_for_namespace("");
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        assert_eq!(cache.stats().hits, 4);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats_for_namespace("a").misses, 0);
+    }
+
+    #[tokio::test]
+    async fn mget_mset_works() {
+        let cache = ZCacheInstance::new();
+        cache
+            .mset(vec![
+                ("key1".to_string(), ZEntry::Int(1), None),
+                (
+                    "key2".to_string(),
+                    ZEntry::Int(2),
+                    Some(Duration::from_secs(10)),
+                ),
+                ("key3".to_string(), ZEntry::Int(3), None),
+            ])
+            .await;
+
+        let values: Vec<Option<i64>> = cache
+            .mget(&["key3", "key1", "key2", "key4"])
+            .into_iter()
+            .map(|entry| entry.and_then(|e| e.as_int()))
+            .collect();
+        assert_eq!(values, vec![Some(3), Some(1), Some(2), None]);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                evictions: 0,
+                expirations: 0,
+                entries: 3,
+                peak_bytes: 3 * (4 + std::mem::size_of::<ZEntry>()),
+                rejections: 0
+            }
+        );
+
+        cache.set_max_entries(Some(2));
+        cache
+            .mset(vec![("key5".to_string(), ZEntry::Int(5), None)])
+            .await;
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains_key("key5"));
+    }
+
+    #[tokio::test]
+    async fn warm_works() {
+        let cache = ZCacheInstance::with_clock(Arc::new(MockClock::new()));
+        cache
+            .warm(
+                vec![
+                    ("key1".to_string(), None),
+                    ("key2".to_string(), Some(Duration::from_secs(10))),
+                    ("key3".to_string(), None),
+                    ("key4".to_string(), None),
+                ],
+                |keys| async move {
+                    keys.iter()
+                        .map(|key| (key != "key4").then(|| ZEntry::Text(key.clone())))
+                        .collect()
+                },
+            )
+            .await;
+
+        for key in ["key1", "key2", "key3"] {
+            assert_eq!(cache.read(key), Some(ZEntry::Text(key.to_string())));
+        }
+        assert!(!cache.contains_key("key4"));
+        assert_eq!(cache.ttl("key2"), Some(Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn write_many_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(1)))
+            .await;
+        clock.advance(Duration::from_secs(2));
+
+        let previous = cache
+            .write_many(vec![
+                ("key1".to_string(), ZEntry::Int(10), None),
+                ("key2".to_string(), ZEntry::Int(20), None),
+                ("key3".to_string(), ZEntry::Int(30), None),
+            ])
+            .await;
+        assert_eq!(previous, vec![Some(ZEntry::Int(1)), None, None]);
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(10)));
+        assert_eq!(cache.read("key2"), Some(ZEntry::Int(20)));
+        assert_eq!(cache.read("key3"), Some(ZEntry::Int(30)));
+    }
+
+    #[tokio::test]
+    async fn purge_expired_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(10)))
+            .await;
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_millis(10)))
+            .await;
+        sleep(Duration::from_millis(20));
+
+        assert_eq!(cache.purge_expired(), vec!["key3".to_string()]);
+        assert_eq!(cache.purge_expired(), Vec::<String>::new());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reap_once_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(1)))
+            .await;
+
+        assert_eq!(cache.reap_once(), 0);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.reap_once(), 1);
+        assert_eq!(cache.reap_once(), 0);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stats().expirations, 1);
+    }
+
+    #[tokio::test]
+    async fn reap_batch_works() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ZCacheInstance::with_clock(clock.clone());
+        for index in 0..10 {
+            cache
+                .write(
+                    &format!("key{}", index),
+                    ZEntry::Int(index),
+                    Some(Duration::from_secs(1)),
+                )
+                .await;
+        }
+        cache.write("key10", ZEntry::Int(10), None).await;
+        clock.advance(Duration::from_secs(1));
+
+        let reaped: Vec<usize> = std::iter::from_fn(|| Some(cache.reap_batch(3)))
+            .take_while(|reaped| *reaped > 0)
+            .collect();
+        assert_eq!(reaped, [3, 3, 3, 1]);
+        assert_eq!(cache.raw_stats().total, 1);
+        assert_eq!(cache.stats().expirations, 10);
+        assert_eq!(cache.reap_batch(0), 0);
+    }
+
+    #[tokio::test]
+    async fn read_arc_works() {
+        let cache = ZCacheInstance::new();
+        let text = "a".repeat(1024 * 1024);
+        cache.write("key1", ZEntry::Text(text.clone()), None).await;
+
+        let first = cache.read_arc("key1").unwrap();
+        let second = cache.read_arc("key1").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.as_text(), Some(text.as_str()));
+        assert!(cache.read_arc("key2").is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                evictions: 0,
+                expirations: 0,
+                entries: 1,
+                peak_bytes: 4 + std::mem::size_of::<ZEntry>() + 1024 * 1024,
+                rejections: 0
+            }
+        );
+
+        // A mismatched update of a shared value doesn't copy it.
+        assert!(cache.increment("key1", 1).is_err());
+        assert!(Arc::ptr_eq(&first, &cache.read_arc("key1").unwrap()));
+        drop((first, second));
+        assert_eq!(cache.delete("key1").and_then(|e| e.into_text()), Some(text));
+    }
+
+    #[test]
+    fn fetch_sync_works() -> Result<(), ZCacheError> {
+        let cache = ZCacheInstance::new();
+        let value = cache.fetch_sync("key1", None, || Some(ZEntry::Int(1)))?;
+        assert_eq!(value.as_int(), Some(1));
+
+        let value = cache.fetch_sync("key1", None, || panic!("Entry should be cached!"))?;
+        assert_eq!(value.as_int(), Some(1));
+
+        let result = cache.fetch_sync("key2", None, || None);
+        assert!(matches!(result, Err(ZCacheError::FetchError(_))));
+        assert!(!cache.contains_key("key2"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_or_compute_works() {
+        let cache = ZCacheInstance::new();
+        let calls = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let value = cache.get_or_compute("key1", None, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(50));
+                        ZEntry::Int(1)
+                    });
+                    assert_eq!(value.as_int(), Some(1));
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(lock(&cache.computing.0).is_empty());
+
+        cache.reset_stats();
+        let value = cache.get_or_compute("key2", None, || ZEntry::Int(2));
+        assert_eq!(value.as_int(), Some(2));
+        assert_eq!(cache.stats().misses, 1);
+        assert_eq!(cache.read("key2").and_then(|e| e.as_int()), Some(2));
+    }
+
+    #[tokio::test]
+    async fn read_typed_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache.write("key2", ZEntry::Text("text".into()), None).await;
+
+        assert_eq!(cache.read_typed::<i64, _>("key1"), Some(1));
+        assert_eq!(cache.read_typed::<String, _>("key1"), None);
+        assert_eq!(cache.read_typed::<String, _>("key2"), Some("text".into()));
+        assert_eq!(cache.read_typed::<i64, _>("key3"), None);
+    }
+
+    #[tokio::test]
+    async fn read_or_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+
+        assert_eq!(cache.read_or("key1", ZEntry::Int(0)).as_int(), Some(1));
+        assert_eq!(cache.read_or("key2", ZEntry::Int(0)).as_int(), Some(0));
+        assert_eq!(
+            cache.read_or_else("key2", || ZEntry::Int(2)).as_int(),
+            Some(2)
+        );
+        assert!(cache.read("key2").is_none());
+    }
+
+    #[tokio::test]
+    async fn list_works() {
+        let cache = ZCacheInstance::new();
+        let list = ZEntry::List(vec![
+            ZEntry::Int(1),
+            ZEntry::List(vec![ZEntry::Text("nested".to_string())]),
+        ]);
+        cache.write("key1", list, None).await;
+
+        let list = cache.read("key1").and_then(|e| e.into_list()).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].as_int(), Some(1));
+        let nested = list[1].as_list().unwrap();
+        assert_eq!(nested[0].as_text(), Some("nested"));
+        assert!(ZEntry::Int(1).as_list().is_none());
+    }
+
+    #[tokio::test]
+    async fn map_works() {
+        let cache = ZCacheInstance::new();
+        let map = ZEntry::Map(HashMap::from([
+            ("id".to_string(), ZEntry::Int(1)),
+            ("name".to_string(), ZEntry::Text("name".to_string())),
+        ]));
+        cache.write("key1", map, None).await;
+
+        let map = cache.read("key1").unwrap();
+        assert_eq!(map.as_map().map(|map| map.len()), Some(2));
+        assert_eq!(map.get("id").and_then(|e| e.as_int()), Some(1));
+        assert_eq!(map.get("name").and_then(|e| e.as_text()), Some("name"));
+        assert!(map.get("missing").is_none());
+        assert!(ZEntry::Int(1).get("id").is_none());
+        assert_eq!(map.into_map().map(|map| map.len()), Some(2));
+    }
+
+    #[tokio::test]
+    async fn write_nx_works() {
+        let cache = ZCacheInstance::new();
+        assert!(cache.write_nx("key1", ZEntry::Int(1), None).await);
+        assert!(!cache.write_nx("key1", ZEntry::Int(2), None).await);
+        assert_eq!(cache.read("key1").and_then(|e| e.as_int()), Some(1));
+
+        let ttl = Some(Duration::from_millis(10));
+        assert!(cache.write_nx("key2", ZEntry::Int(1), ttl).await);
+        sleep(Duration::from_millis(20));
+        assert!(cache.write_nx("key2", ZEntry::Int(2), None).await);
+        assert_eq!(cache.read("key2").and_then(|e| e.as_int()), Some(2));
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+
+        let expected = cache.read("key1").unwrap();
+        assert!(cache.compare_and_swap("key1", &expected, ZEntry::Int(2), None));
+        assert!(!cache.compare_and_swap("key1", &expected, ZEntry::Int(3), None));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(2)));
+        assert!(!cache.compare_and_swap("key2", &expected, ZEntry::Int(3), None));
+        assert!(!cache.contains_key("key2"));
+    }
+
+    #[tokio::test]
+    async fn write_if_version_works() {
+        let cache = ZCacheInstance::new();
+        cache.write("key1", ZEntry::Int(1), None).await;
+
+        let (value, version) = cache.read_versioned("key1").unwrap();
+        assert_eq!(value, ZEntry::Int(1));
+        assert!(cache.write_if_version("key1", ZEntry::Int(2), version, None));
+        assert!(!cache.write_if_version("key1", ZEntry::Int(3), version, None));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(2)));
+
+        let (_, version) = cache.read_versioned("key1").unwrap();
+        cache.increment("key1", 1).unwrap();
+        assert!(!cache.write_if_version("key1", ZEntry::Int(4), version, None));
+        assert!(!cache.write_if_version("key2", ZEntry::Int(4), version, None));
+        assert!(cache.read_versioned("key2").is_none());
+
+        cache.write("key3", ZEntry::Text("text".into()), None).await;
+        let (_, version) = cache.read_versioned("key3").unwrap();
+        assert!(cache.increment("key3", 1).is_err());
+        assert!(cache.add_float("key3", 1.0).is_err());
+        assert!(cache.toggle("key3").is_err());
+        assert!(cache.write_if_version("key3", ZEntry::Int(i64::MAX), version, None));
+
+        let (_, version) = cache.read_versioned("key3").unwrap();
+        assert!(cache.append("key3", "text").is_err());
+        let result = cache.increment("key3", 1);
+        assert!(matches!(result, Err(ZCacheError::Overflow(_))));
+        assert!(cache.write_if_version("key3", ZEntry::Int(4), version, None));
+    }
+
+    #[test]
+    fn equality_works() {
+        assert_eq!(ZEntry::Int(1), ZEntry::Int(1));
+        assert_ne!(ZEntry::Int(1), ZEntry::Int(2));
+        assert_ne!(ZEntry::Int(1), ZEntry::Float(1.0));
+        assert_eq!(ZEntry::Float(1.5), ZEntry::Float(1.5));
+        assert_eq!(ZEntry::Float(f64::NAN), ZEntry::Float(f64::NAN));
+        assert_ne!(ZEntry::Float(0.0), ZEntry::Float(-0.0));
+        assert_eq!(
+            ZEntry::Text("text".to_string()),
+            ZEntry::Text("text".to_string())
+        );
+        assert_ne!(ZEntry::Bool(true), ZEntry::Bool(false));
+        assert_eq!(ZEntry::Bytes(vec![1]), ZEntry::Bytes(vec![1]));
+        assert_eq!(
+            ZEntry::List(vec![ZEntry::Float(f64::NAN)]),
+            ZEntry::List(vec![ZEntry::Float(f64::NAN)])
+        );
+        assert_ne!(
+            ZEntry::Map(HashMap::from([("a".to_string(), ZEntry::Int(1))])),
+            ZEntry::Map(HashMap::from([("a".to_string(), ZEntry::Int(2))]))
+        );
+    }
+
+    #[tokio::test]
+    async fn update_works() {
+        let cache = ZCacheInstance::new();
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::from_secs(10)))
+            .await;
+
+        let double = |entry: &mut ZEntry| {
+            if let ZEntry::Int(value) = entry {
+                *value *= 2;
+            }
+        };
+        assert!(cache.update("key1", double));
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(2)));
+        assert!(cache.ttl("key1").unwrap() <= Duration::from_secs(10));
+
+        assert!(!cache.update("key2", double));
+        assert!(!cache.contains_key("key2"));
+    }
+
+    #[tokio::test]
+    async fn configure_works() {
+        let cache = ZCacheInstance::new();
+        let config = ZCacheConfig::new()
+            .default_ttl(Duration::from_secs(1))
+            .max_entries(2);
+        assert!(cache.configure(config).is_none());
+
+        cache.write("key1", ZEntry::Int(1), None).await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(10)))
+            .await;
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(10)))
+            .await;
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key("key1"));
+        assert!(cache.ttl("key2").unwrap() > Duration::from_secs(1));
+
+        cache.write("key2", ZEntry::Int(2), None).await;
+        assert!(cache.ttl("key2").unwrap() <= Duration::from_secs(1));
+        sleep(Duration::from_millis(1100));
+        assert!(cache.read("key2").is_none());
+        assert!(cache.read("key3").is_some());
+
+        let config = ZCacheConfig::new().reaper_interval(Duration::from_millis(10));
+        let reaper = cache.configure(config).expect("Reaper should be started!");
+        reaper.stop();
+    }
+
+    #[tokio::test]
+    async fn ttl_bounds_work() {
+        let cache = ZCacheInstance::with_clock(Arc::new(MockClock::new()));
+        let config = ZCacheConfig::new()
+            .min_ttl(Duration::from_secs(1))
+            .max_ttl(Duration::from_secs(60));
+        cache.configure(config);
+
+        cache
+            .write("key1", ZEntry::Int(1), Some(Duration::from_millis(1)))
+            .await;
+        cache
+            .write("key2", ZEntry::Int(2), Some(Duration::from_secs(10)))
+            .await;
+        cache
+            .write("key3", ZEntry::Int(3), Some(Duration::from_secs(3600)))
+            .await;
+        cache.write("key4", ZEntry::Int(4), None).await;
+
+        assert_eq!(cache.ttl("key1"), Some(Duration::from_secs(1)));
+        assert_eq!(cache.ttl("key2"), Some(Duration::from_secs(10)));
+        assert_eq!(cache.ttl("key3"), Some(Duration::from_secs(60)));
+        assert_eq!(cache.ttl("key4"), Some(Duration::from_secs(60)));
+
+        cache.increment("key5", 1).unwrap();
+        cache.add_float("key6", 1.0).unwrap();
+        cache.toggle("key7").unwrap();
+        cache.append("key8", "text").unwrap();
+        for key in ["key5", "key6", "key7", "key8"] {
+            assert_eq!(cache.ttl(key), Some(Duration::from_secs(60)));
+        }
+
+        let now = Duration::from_millis(cache.now() as u64);
+        let expires_at = UNIX_EPOCH + now + Duration::from_secs(3600);
+        cache.write_until("key9", ZEntry::Int(9), expires_at).await;
+        assert_eq!(cache.ttl("key9"), Some(Duration::from_secs(60)));
+        cache.write_until("key9", ZEntry::Int(9), UNIX_EPOCH).await;
+        assert!(!cache.contains_key("key9"));
+
+        let fresh_for = Duration::from_millis(1);
+        cache
+            .fetch_swr("key10", fresh_for, fresh_for, || async {
+                Some(ZEntry::Int(10))
+            })
+            .await
+            .unwrap();
+        assert_eq!(cache.ttl("key10"), Some(Duration::from_secs(1)));
+        cache
+            .fetch_refresh_ahead("key11", Duration::from_secs(3600), fresh_for, || async {
+                Some(ZEntry::Int(11))
+            })
+            .await
+            .unwrap();
+        assert_eq!(cache.ttl("key11"), Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn write_until_works() {
+        let cache = ZCacheInstance::new();
+        let expires_at = SystemTime::now() + Duration::from_millis(500);
+        cache.write_until("key1", ZEntry::Int(1), expires_at).await;
+        cache.write_until("key2", ZEntry::Int(2), UNIX_EPOCH).await;
+
+        assert_eq!(cache.read("key1"), Some(ZEntry::Int(1)));
+        assert!(cache.ttl("key1").unwrap() <= Duration::from_millis(500));
+        assert!(cache.read("key2").is_none());
+
+        sleep(Duration::from_millis(600));
+        assert!(cache.read("key1").is_none());
+    }
+
+    #[tokio::test]
+    async fn write_jittered_works() {
+        let write_all = |seed| async move {
+            let clock = Arc::new(MockClock::new());
+            let cache = ZCacheInstance::with_clock(clock);
+            cache.set_jitter_seed(seed);
+            let mut ttls = Vec::new();
+            for i in 0..100 {
+                let key = format!("key{}", i);
+                cache
+                    .write_jittered(&key, ZEntry::Int(i), Duration::from_secs(100), 0.2)
+                    .await;
+                ttls.push(cache.ttl(&key).unwrap());
+            }
+            ttls
+        };
+
+        let ttls = write_all(1).await;
+        let min = *ttls.iter().min().unwrap();
+        let max = *ttls.iter().max().unwrap();
+        assert!(min >= Duration::from_secs(80));
+        assert!(max <= Duration::from_secs(120));
+        assert!(max - min > Duration::from_secs(20));
+        assert_eq!(write_all(1).await, ttls);
+        assert_ne!(write_all(2).await, ttls);
+
+        let cache = ZCacheInstance::new();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            cache
+                .write_jittered(&key, ZEntry::Int(i), Duration::MAX, 1.0)
+                .await;
+            assert_eq!(cache.read(&key), Some(ZEntry::Int(i)));
         }
-        Ok(())
     }
 }